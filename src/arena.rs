@@ -0,0 +1,95 @@
+use std::cell::RefCell;
+
+use typed_arena::Arena as TypedArena;
+
+use crate::env::Env;
+use crate::nix_expr::Expr;
+use crate::value::ThunkState;
+
+/// Owns the expressions, environments and thunks of an evaluation, which is
+/// where the `'arena` lifetime comes from.
+///
+/// Everything is freed at once when the arena is dropped, so these can
+/// refer to each other with plain references, even in cycles, like the
+/// thunks of a `rec` set and the environment they're evaluated in.
+#[derive(Default)]
+pub struct Arena<'arena> {
+    exprs: TypedArena<Expr>,
+    envs: TypedArena<Env<'arena>>,
+    thunks: TypedArena<RefCell<ThunkState<'arena>>>,
+}
+
+impl<'arena> Arena<'arena> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn alloc_expr(&'arena self, expr: Expr) -> &'arena Expr {
+        self.exprs.alloc(expr)
+    }
+
+    pub fn alloc_env(&'arena self, env: Env<'arena>) -> &'arena Env<'arena> {
+        self.envs.alloc(env)
+    }
+
+    pub(crate) fn alloc_thunk(
+        &'arena self,
+        state: ThunkState<'arena>,
+    ) -> &'arena RefCell<ThunkState<'arena>> {
+        self.thunks.alloc(RefCell::new(state))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::eval::tests::eval_list;
+    use crate::eval::EvalState;
+    use crate::pos::Pos;
+    use crate::value::Value;
+    use crate::Symbol;
+
+    #[test]
+    fn lambdas_share_expressions() {
+        let arena = Arena::new();
+        let state = EvalState::new(&arena);
+        let elems = eval_list(
+            &state,
+            r#"let g = y: { ${"f"} = x: y; }; in [ (g 1).f (g 2).f ]"#,
+        );
+        let lambdas = elems
+            .into_iter()
+            .map(
+                |elem| match state.force_value(elem, &Pos::Undefined).unwrap() {
+                    Value::Lambda(lambda) => lambda,
+                    value => panic!("expected a lambda, got {}", value),
+                },
+            )
+            .collect::<Vec<_>>();
+        // Each call closes over a different environment, but the function
+        // isn't copied, not even to give it the name of its attribute.
+        assert!(std::ptr::eq(lambdas[0].fun, lambdas[1].fun));
+        assert!(!std::ptr::eq(lambdas[0].env, lambdas[1].env));
+        assert_eq!(lambdas[0].fun.name.get(), Some(Symbol::new("f")));
+    }
+
+    #[test]
+    fn values_outlive_evaluation() {
+        let arena = Arena::new();
+        let state = EvalState::new(&arena);
+        let value = {
+            let expr = state
+                .parse_expr_from_string("rec { a = [ b ]; b = { inherit a; }; }", Path::new("/"))
+                .unwrap();
+            state.eval(expr).unwrap()
+        };
+        assert_eq!(
+            state.force_value_deep(value).unwrap().to_string(),
+            "{ a = [ { a = «repeated»; } ]; b = «repeated»; }"
+        );
+    }
+}
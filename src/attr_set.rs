@@ -1,49 +1,164 @@
 use std::cmp::{Ord, Ordering, PartialOrd};
 use std::collections::HashMap;
+use std::rc::Rc;
 
 use crate::pos::Pos;
-use crate::symbol_table::Symbol;
+use crate::symbol_table::{Symbol, SymbolTable};
 use crate::Value;
 
 #[derive(Debug, PartialEq)]
-pub struct Attr<'arena> {
-    pub name: Symbol<'arena>,
-    pub value: &'arena AttrValue<'arena>,
+pub struct Attr<'a, 'arena> {
+    pub name: Symbol,
+    pub value: &'a AttrValue<'arena>,
 }
 
-impl<'arena> Eq for Attr<'arena> {}
+impl Eq for Attr<'_, '_> {}
 
-impl PartialOrd for Attr<'_> {
+impl PartialOrd for Attr<'_, '_> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl Ord for Attr<'_> {
+impl Ord for Attr<'_, '_> {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.name.cmp(other.name)
+        self.name.cmp(&other.name)
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct AttrValue<'arena> {
     pub value: Value<'arena>,
-    pub pos: Pos<'arena>,
+    pub pos: Pos,
 }
 
 impl<'arena> Eq for AttrValue<'arena> {}
 
-#[derive(Debug, PartialEq)]
-pub struct Bindings<'arena>(pub HashMap<Symbol<'arena>, AttrValue<'arena>>);
+/// The attributes of a set. The map is shared between copies of the set
+/// and only copied when one of them is modified.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Bindings<'arena>(pub Rc<HashMap<Symbol, AttrValue<'arena>>>);
 
 impl<'arena> Bindings<'arena> {
-    fn sorted(&'arena self) -> Vec<Attr<'arena>> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, name: Symbol) -> Option<&AttrValue<'arena>> {
+        self.0.get(&name)
+    }
+
+    pub fn insert(&mut self, name: Symbol, value: Value<'arena>, pos: Pos) {
+        Rc::make_mut(&mut self.0).insert(name, AttrValue { value, pos });
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut AttrValue<'arena>> {
+        Rc::make_mut(&mut self.0).values_mut()
+    }
+
+    /// `self // other`: the attributes of both, with `other`'s taking
+    /// precedence. The smaller set is added to a copy of the larger one, in
+    /// O(n + m). The copy is only skipped if nothing else refers to the
+    /// larger set, which is the case for a fresh set like the result of
+    /// `b // c` in `a // b // c`. A set from a variable or attribute is
+    /// shared with the thunk it came from, so it's always copied.
+    pub fn update(mut self, mut other: Self) -> Self {
+        if other.is_empty() {
+            return self;
+        }
+        if self.is_empty() {
+            return other;
+        }
+        if self.len() > other.len() {
+            let attrs = Rc::make_mut(&mut self.0);
+            for (&name, value) in other.0.iter() {
+                attrs.insert(name, value.clone());
+            }
+            self
+        } else {
+            let attrs = Rc::make_mut(&mut other.0);
+            for (&name, value) in self.0.iter() {
+                attrs.entry(name).or_insert_with(|| value.clone());
+            }
+            other
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The attributes, sorted by name.
+    pub fn sorted(&self) -> Vec<Attr<'_, 'arena>> {
         let mut ret: Vec<_> = self
             .0
             .iter()
-            .map(|(name, value)| Attr { name, value })
+            .map(|(&name, value)| Attr { name, value })
             .collect();
-        ret.sort();
+        SymbolTable::global().sort_by_name(&mut ret, |attr| attr.name);
         ret
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn bindings(attrs: &[(&str, i64)]) -> Bindings<'static> {
+        let mut bindings = Bindings::new();
+        for &(name, n) in attrs {
+            bindings.insert(Symbol::new(name), Value::Int(n), Pos::Undefined);
+        }
+        bindings
+    }
+
+    fn get(bindings: &Bindings<'_>, name: &str) -> Option<Value<'static>> {
+        bindings
+            .get(Symbol::new(name))
+            .map(|attr| match attr.value {
+                Value::Int(n) => Value::Int(n),
+                ref value => panic!("unexpected value {}", value),
+            })
+    }
+
+    #[test]
+    fn update_prefers_right() {
+        for (left, right) in &[
+            (
+                bindings(&[("a", 1), ("b", 2), ("c", 3)]),
+                bindings(&[("b", 4)]),
+            ),
+            (
+                bindings(&[("b", 2)]),
+                bindings(&[("a", 1), ("b", 4), ("c", 3)]),
+            ),
+        ] {
+            let updated = left.clone().update(right.clone());
+            assert_eq!(get(&updated, "b"), Some(Value::Int(4)));
+            assert_eq!(updated.len(), left.len().max(right.len()));
+        }
+    }
+
+    #[test]
+    fn update_shares_unchanged_sets() {
+        let attrs = bindings(&[("a", 1)]);
+        let updated = attrs.clone().update(Bindings::new());
+        assert!(Rc::ptr_eq(&updated.0, &attrs.0));
+        let updated = Bindings::new().update(attrs.clone());
+        assert!(Rc::ptr_eq(&updated.0, &attrs.0));
+    }
+
+    #[test]
+    fn update_copies_shared_sets() {
+        let attrs = bindings(&[("a", 1), ("b", 2)]);
+        let updated = attrs.clone().update(bindings(&[("a", 3)]));
+        assert_eq!(get(&attrs, "a"), Some(Value::Int(1)));
+        assert_eq!(get(&updated, "a"), Some(Value::Int(3)));
+    }
+}
@@ -0,0 +1,1529 @@
+//! A lossless concrete syntax tree.
+//!
+//! Unlike [`Expr`], the tree keeps every byte of the source, including
+//! comments, whitespace and the original spelling of literals, so printing
+//! it gives back exactly what was parsed. This is what formatters and
+//! refactoring tools want. Broken code still produces a tree, with `Error`
+//! nodes around anything that couldn't be parsed and empty `Error` nodes
+//! where something is missing.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use nom::character::complete::multispace1;
+use nom::IResult;
+
+use crate::env::Level;
+use crate::err::{NixError, NixResult};
+use crate::nix_expr::{
+    AttrName, AttrPath, BinOp, Expr, ExprAssert, ExprAttrs, ExprConcatStrings, ExprIf, ExprLambda,
+    ExprLet, ExprOpHasAttr, ExprSelect, ExprWith, Formal, Formals, OpKind,
+};
+use crate::parser::{
+    self, absolute_base_path, binary_op_expr, block_comment, call_builtin, count, ind_string_parts,
+    is_path_char, lex_ind_string_raw, lex_path, lex_path_seg, lex_word, line_comment, not, punct,
+    resolve_path, skip_ind_string_start, strip_indentation, unescape_string, var, Assoc,
+    IndStringPart, WordKind, BINARY_OPS, KEYWORDS, NEGATE_PREC, NOT_PREC,
+};
+use crate::pos::{KnownPos, Pos};
+use crate::symbol_table::Symbol;
+use crate::value::NixFloat;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SyntaxKind {
+    // Tokens.
+    Whitespace,
+    /// A `#` or `/* */` comment.
+    Comment,
+    Ident,
+    Keyword,
+    Int,
+    Float,
+    /// A path literal, or a piece of one between antiquotations.
+    PathContent,
+    /// `<nixpkgs>`
+    SearchPath,
+    Uri,
+    /// Operators, brackets and string delimiters.
+    Punct,
+    /// Literal text in a string, with escapes left as they were written.
+    StringContent,
+    /// `${`
+    InterpolStart,
+    /// The `}` closing an antiquotation.
+    InterpolEnd,
+    /// A character which doesn't start any token.
+    Unknown,
+
+    // Nodes.
+    Root,
+    /// Code which couldn't be parsed, or, if empty, something missing.
+    Error,
+    Var,
+    /// An integer, float, URI or search path.
+    Literal,
+    /// A path, which may contain antiquotations.
+    Path,
+    String,
+    IndString,
+    /// `${expr}` in a string, path or attribute path.
+    Interpol,
+    Paren,
+    List,
+    /// `{ ... }` or `rec { ... }`.
+    AttrSet,
+    /// `let { ... }`
+    LegacyLet,
+    /// `path = expr;`
+    Binding,
+    /// `inherit a b;` or `inherit (expr) a b;`
+    Inherit,
+    /// The `(expr)` in `inherit (expr) a b;`.
+    InheritFrom,
+    AttrPath,
+    /// `e.a.b` or `e.a.b or default`.
+    Select,
+    /// `e ? a.b`
+    HasAttr,
+    Apply,
+    /// `!e` or `-e`.
+    UnaryOp,
+    BinOp,
+    If,
+    Assert,
+    With,
+    /// `let ... in body`
+    LetIn,
+    Lambda,
+    Formals,
+    Formal,
+}
+
+impl SyntaxKind {
+    pub fn is_trivia(self) -> bool {
+        self == SyntaxKind::Whitespace || self == SyntaxKind::Comment
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyntaxToken {
+    kind: SyntaxKind,
+    text: String,
+}
+
+impl SyntaxToken {
+    pub fn new(kind: SyntaxKind, text: impl Into<String>) -> Self {
+        SyntaxToken {
+            kind,
+            text: text.into(),
+        }
+    }
+
+    pub fn kind(&self) -> SyntaxKind {
+        self.kind
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Whether this is the punctuation or keyword `text`, rather than, say,
+    /// a string containing it.
+    fn is(&self, text: &str) -> bool {
+        match self.kind {
+            SyntaxKind::Punct
+            | SyntaxKind::Keyword
+            | SyntaxKind::InterpolStart
+            | SyntaxKind::InterpolEnd => self.text == text,
+            _ => false,
+        }
+    }
+}
+
+impl Display for SyntaxToken {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SyntaxElement {
+    Node(SyntaxNode),
+    Token(SyntaxToken),
+}
+
+impl SyntaxElement {
+    pub fn kind(&self) -> SyntaxKind {
+        match self {
+            SyntaxElement::Node(node) => node.kind(),
+            SyntaxElement::Token(token) => token.kind(),
+        }
+    }
+}
+
+impl Display for SyntaxElement {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SyntaxElement::Node(node) => node.fmt(f),
+            SyntaxElement::Token(token) => token.fmt(f),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyntaxNode {
+    kind: SyntaxKind,
+    children: Vec<SyntaxElement>,
+}
+
+impl SyntaxNode {
+    pub fn new(kind: SyntaxKind, children: Vec<SyntaxElement>) -> Self {
+        SyntaxNode { kind, children }
+    }
+
+    pub fn kind(&self) -> SyntaxKind {
+        self.kind
+    }
+
+    pub fn children(&self) -> &[SyntaxElement] {
+        &self.children
+    }
+
+    pub fn children_mut(&mut self) -> &mut Vec<SyntaxElement> {
+        &mut self.children
+    }
+
+    pub fn child_nodes(&self) -> impl Iterator<Item = &SyntaxNode> {
+        self.children.iter().filter_map(|child| match child {
+            SyntaxElement::Node(node) => Some(node),
+            SyntaxElement::Token(_) => None,
+        })
+    }
+
+    /// Every token in this node, in source order.
+    pub fn tokens(&self) -> Vec<&SyntaxToken> {
+        let mut tokens = Vec::new();
+        self.collect_tokens(&mut tokens);
+        tokens
+    }
+
+    fn collect_tokens<'a>(&'a self, tokens: &mut Vec<&'a SyntaxToken>) {
+        for child in &self.children {
+            match child {
+                SyntaxElement::Node(node) => node.collect_tokens(tokens),
+                SyntaxElement::Token(token) => tokens.push(token),
+            }
+        }
+    }
+
+    /// Whether there are any `Error` nodes in this node.
+    pub fn has_errors(&self) -> bool {
+        self.kind == SyntaxKind::Error || self.child_nodes().any(SyntaxNode::has_errors)
+    }
+
+    /// Lower this node into an [`Expr`] for evaluation, giving the same
+    /// result as [`parser::parse`](crate::parser::parse) on its text.
+    ///
+    /// Positions count from the start of the node, so for the `Root` from
+    /// [`parse`] they're positions in the file. A tree with `Error` nodes
+    /// fails with the parser's error for its text, which says what was
+    /// expected there.
+    pub fn to_expr(&self, file: &str, base_path: &Path) -> NixResult<Expr> {
+        let mut lower = Lower {
+            file: Rc::from(file),
+            base_path: absolute_base_path(base_path),
+            node_pos: HashMap::new(),
+            token_pos: HashMap::new(),
+        };
+        lower.record_positions(self, &mut (1, 1));
+        if let Some(error) = self.first_error() {
+            return Err(match parser::parse(&self.to_string(), file, base_path) {
+                Err(err) => err,
+                Ok(_) => lower.error(error),
+            });
+        }
+        if self.kind == SyntaxKind::Root {
+            match self.child_nodes().next() {
+                Some(node) => lower.expr(node),
+                None => Err(NixError::Parse(
+                    "syntax error, unexpected end of file".to_owned(),
+                    lower.pos(self).to_owned(),
+                )),
+            }
+        } else {
+            lower.expr(self)
+        }
+    }
+
+    /// The first `Error` node in this node, in source order.
+    fn first_error(&self) -> Option<&SyntaxNode> {
+        if self.kind == SyntaxKind::Error {
+            return Some(self);
+        }
+        self.child_nodes().find_map(SyntaxNode::first_error)
+    }
+
+    /// The tokens directly in this node which aren't trivia.
+    fn child_tokens(&self) -> impl Iterator<Item = &SyntaxToken> {
+        self.children.iter().filter_map(|child| match child {
+            SyntaxElement::Token(token) if !token.kind.is_trivia() => Some(token),
+            _ => None,
+        })
+    }
+
+    fn has_token(&self, text: &str) -> bool {
+        self.child_tokens().any(|token| token.is(text))
+    }
+}
+
+impl Display for SyntaxNode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.children.iter().try_for_each(|child| child.fmt(f))
+    }
+}
+
+/// Parse `source` into a lossless syntax tree, with a `Root` node at the top.
+pub fn parse(source: &str) -> SyntaxNode {
+    let mut parser = CstParser {
+        tokens: tokenize(source),
+        pos: 0,
+        stack: vec![(SyntaxKind::Root, Vec::new())],
+    };
+    if parser.nth(0).is_some() {
+        parser.expr();
+    }
+    if parser.nth(0).is_some() {
+        parser.start_node(SyntaxKind::Error);
+        while parser.nth(0).is_some() {
+            parser.bump();
+        }
+        parser.finish_node();
+    }
+    parser.flush_trivia();
+    let (kind, children) = parser.stack.pop().unwrap();
+    SyntaxNode::new(kind, children)
+}
+
+/// What the lexer is in the middle of, which decides how the next token is
+/// lexed.
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Brace,
+    Interpol,
+    String,
+    IndString,
+    Path,
+}
+
+/// Split `source` into tokens, keeping trivia. Like upstream's lexer, this
+/// keeps a stack of modes to tell when braces close antiquotations, and
+/// lexes strings and paths differently to the rest of the code.
+fn tokenize(source: &str) -> Vec<SyntaxToken> {
+    let mut tokens: Vec<SyntaxToken> = Vec::new();
+    let mut modes = Vec::new();
+    let mut i = source;
+    while !i.is_empty() {
+        let token = match modes.last() {
+            Some(Mode::String) => lex_string(i, &mut modes),
+            Some(Mode::IndString) => lex_ind_string(i, &mut modes),
+            Some(Mode::Path) => match lex_path_part(i, &mut modes) {
+                Some(token) => token,
+                None => continue,
+            },
+            _ => lex_code(i, &mut modes),
+        };
+        let (kind, len) = token;
+        match tokens.last_mut() {
+            Some(last) if kind == SyntaxKind::StringContent && last.kind == kind => {
+                last.text.push_str(&i[..len]);
+            }
+            _ => tokens.push(SyntaxToken::new(kind, &i[..len])),
+        }
+        i = &i[len..];
+    }
+    tokens
+}
+
+fn lex_code(i: &str, modes: &mut Vec<Mode>) -> (SyntaxKind, usize) {
+    let whitespace: IResult<&str, &str> = multispace1(i);
+    if let Ok((rest, _)) = whitespace {
+        return (SyntaxKind::Whitespace, i.len() - rest.len());
+    }
+    if let Ok((rest, _)) = line_comment(i).or_else(|_| block_comment(i)) {
+        return (SyntaxKind::Comment, i.len() - rest.len());
+    }
+
+    let p = punct(i);
+    match lex_word(i) {
+        Some((kind, len)) if len > p.map_or(0, str::len) => {
+            return match kind {
+                WordKind::Id if KEYWORDS.contains(&&i[..len]) => (SyntaxKind::Keyword, len),
+                WordKind::Id => (SyntaxKind::Ident, len),
+                WordKind::Int => (SyntaxKind::Int, len),
+                WordKind::Float => (SyntaxKind::Float, len),
+                // The antiquotation is lexed separately, in path mode.
+                WordKind::PathStart | WordKind::HPathStart => {
+                    modes.push(Mode::Path);
+                    (SyntaxKind::PathContent, len - 2)
+                }
+                WordKind::Path | WordKind::HPath => {
+                    modes.push(Mode::Path);
+                    (SyntaxKind::PathContent, len)
+                }
+                WordKind::SPath => (SyntaxKind::SearchPath, len),
+                WordKind::Uri => (SyntaxKind::Uri, len),
+            };
+        }
+        _ => {}
+    }
+
+    match p {
+        Some("\"") => modes.push(Mode::String),
+        Some("''") => modes.push(Mode::IndString),
+        Some("{") => modes.push(Mode::Brace),
+        Some("${") => {
+            modes.push(Mode::Interpol);
+            return (SyntaxKind::InterpolStart, 2);
+        }
+        Some("}") => {
+            if modes.pop() == Some(Mode::Interpol) {
+                return (SyntaxKind::InterpolEnd, 1);
+            }
+        }
+        Some(_) => {}
+        None => return (SyntaxKind::Unknown, i.chars().next().unwrap().len_utf8()),
+    }
+    (SyntaxKind::Punct, p.unwrap().len())
+}
+
+fn lex_string(i: &str, modes: &mut Vec<Mode>) -> (SyntaxKind, usize) {
+    if i.starts_with('"') {
+        modes.pop();
+        return (SyntaxKind::Punct, 1);
+    }
+    if i.starts_with("${") {
+        modes.push(Mode::Interpol);
+        return (SyntaxKind::InterpolStart, 2);
+    }
+
+    // This only stops at ASCII characters, so always at a char boundary.
+    let b = i.as_bytes();
+    let mut n = 0;
+    while n < b.len() {
+        match (b[n], b.get(n + 1)) {
+            (b'"', _) | (b'$', Some(b'{')) => break,
+            (b'\\', Some(_)) | (b'$', Some(b'$')) => n += 2,
+            _ => n += 1,
+        }
+    }
+    (SyntaxKind::StringContent, n)
+}
+
+fn lex_ind_string(i: &str, modes: &mut Vec<Mode>) -> (SyntaxKind, usize) {
+    if let Some(rest) = i.strip_prefix("''") {
+        // `'''`, `''$` and `''\x` are escapes.
+        return if rest.starts_with('\'') || rest.starts_with('$') {
+            (SyntaxKind::StringContent, 3)
+        } else if let Some(rest) = rest.strip_prefix('\\') {
+            let escaped = rest.chars().next().map_or(0, char::len_utf8);
+            (SyntaxKind::StringContent, 3 + escaped)
+        } else {
+            modes.pop();
+            (SyntaxKind::Punct, 2)
+        };
+    }
+    if i.starts_with("${") {
+        modes.push(Mode::Interpol);
+        return (SyntaxKind::InterpolStart, 2);
+    }
+    // A lone `$` or `'` is plain text too.
+    (SyntaxKind::StringContent, lex_ind_string_raw(i).max(1))
+}
+
+/// The rest of a path after an antiquotation, or `None` once the path has
+/// ended.
+fn lex_path_part(i: &str, modes: &mut Vec<Mode>) -> Option<(SyntaxKind, usize)> {
+    if i.starts_with("${") {
+        modes.push(Mode::Interpol);
+        return Some((SyntaxKind::InterpolStart, 2));
+    }
+    let len = lex_path(i)
+        .max(lex_path_seg(i))
+        .max(count(i.as_bytes(), 0, is_path_char));
+    if len == 0 {
+        modes.pop();
+        None
+    } else {
+        Some((SyntaxKind::PathContent, len))
+    }
+}
+
+/// Builds the tree from tokens by recursive descent, mirroring
+/// [`crate::parser`]'s grammar. Nodes under construction are kept on a
+/// stack; trivia goes in whichever node is open when the next real token is
+/// added, so nodes start and end with real tokens.
+struct CstParser {
+    tokens: Vec<SyntaxToken>,
+    pos: usize,
+    stack: Vec<(SyntaxKind, Vec<SyntaxElement>)>,
+}
+
+impl CstParser {
+    /// The `n`th token from here which isn't trivia.
+    fn nth(&self, n: usize) -> Option<&SyntaxToken> {
+        self.tokens[self.pos..]
+            .iter()
+            .filter(|token| !token.kind.is_trivia())
+            .nth(n)
+    }
+
+    fn nth_is(&self, n: usize, text: &str) -> bool {
+        self.nth(n).is_some_and(|token| token.is(text))
+    }
+
+    fn at(&self, text: &str) -> bool {
+        self.nth_is(0, text)
+    }
+
+    fn at_kind(&self, kind: SyntaxKind) -> bool {
+        self.nth(0).is_some_and(|token| token.kind == kind)
+    }
+
+    /// The very next token, trivia or not.
+    fn current(&self) -> Option<&SyntaxToken> {
+        self.tokens.get(self.pos)
+    }
+
+    fn push(&mut self, element: SyntaxElement) {
+        self.stack.last_mut().unwrap().1.push(element);
+    }
+
+    fn flush_trivia(&mut self) {
+        while let Some(token) = self.current() {
+            if !token.kind.is_trivia() {
+                break;
+            }
+            let token = token.clone();
+            self.push(SyntaxElement::Token(token));
+            self.pos += 1;
+        }
+    }
+
+    fn bump(&mut self) {
+        self.flush_trivia();
+        if let Some(token) = self.current() {
+            let token = token.clone();
+            self.push(SyntaxElement::Token(token));
+            self.pos += 1;
+        }
+    }
+
+    fn start_node(&mut self, kind: SyntaxKind) {
+        self.flush_trivia();
+        self.stack.push((kind, Vec::new()));
+    }
+
+    fn finish_node(&mut self) {
+        let (kind, children) = self.stack.pop().unwrap();
+        self.push(SyntaxElement::Node(SyntaxNode::new(kind, children)));
+    }
+
+    /// Remember where a node might start, for when that only becomes clear
+    /// later, like the left operand of a binary operator.
+    fn checkpoint(&mut self) -> usize {
+        self.flush_trivia();
+        self.stack.last().unwrap().1.len()
+    }
+
+    fn start_node_at(&mut self, checkpoint: usize, kind: SyntaxKind) {
+        let children = self.stack.last_mut().unwrap().1.split_off(checkpoint);
+        self.stack.push((kind, children));
+    }
+
+    fn missing(&mut self) {
+        self.start_node(SyntaxKind::Error);
+        self.finish_node();
+    }
+
+    fn expect(&mut self, text: &str) {
+        if self.at(text) {
+            self.bump();
+        } else {
+            self.missing();
+        }
+    }
+
+    fn expect_kind(&mut self, kind: SyntaxKind) {
+        if self.at_kind(kind) {
+            self.bump();
+        } else {
+            self.missing();
+        }
+    }
+
+    fn expr(&mut self) {
+        if self.at_kind(SyntaxKind::Ident) && (self.nth_is(1, ":") || self.nth_is(1, "@")) {
+            self.start_node(SyntaxKind::Lambda);
+            self.bump();
+            if self.at("@") {
+                self.bump();
+                self.formals();
+            }
+            self.expect(":");
+            self.expr();
+            self.finish_node();
+        } else if self.at_formals() {
+            self.start_node(SyntaxKind::Lambda);
+            self.formals();
+            if self.at("@") {
+                self.bump();
+                self.expect_kind(SyntaxKind::Ident);
+            }
+            self.expect(":");
+            self.expr();
+            self.finish_node();
+        } else if self.at("assert") || self.at("with") {
+            let kind = if self.at("assert") {
+                SyntaxKind::Assert
+            } else {
+                SyntaxKind::With
+            };
+            self.start_node(kind);
+            self.bump();
+            self.expr();
+            self.expect(";");
+            self.expr();
+            self.finish_node();
+        } else if self.at("let") && !self.nth_is(1, "{") {
+            self.start_node(SyntaxKind::LetIn);
+            self.bump();
+            self.binds();
+            self.expect("in");
+            self.expr();
+            self.finish_node();
+        } else if self.at("if") {
+            self.start_node(SyntaxKind::If);
+            self.bump();
+            self.expr();
+            self.expect("then");
+            self.expr();
+            self.expect("else");
+            self.expr();
+            self.finish_node();
+        } else {
+            self.expr_op(0);
+        }
+    }
+
+    /// Whether a `{` starts formal arguments rather than an attribute set.
+    fn at_formals(&self) -> bool {
+        if !self.at("{") {
+            return false;
+        }
+        let is_ident = |n| self.nth(n).is_some_and(|t| t.kind == SyntaxKind::Ident);
+        let then_colon = |n| self.nth_is(n, ":") || self.nth_is(n, "@");
+        if self.nth_is(1, "}") {
+            then_colon(2)
+        } else if is_ident(1) {
+            self.nth_is(2, ",") || self.nth_is(2, "?") || (self.nth_is(2, "}") && then_colon(3))
+        } else {
+            self.nth_is(1, "...")
+        }
+    }
+
+    fn formals(&mut self) {
+        self.start_node(SyntaxKind::Formals);
+        self.expect("{");
+        loop {
+            if self.at("...") {
+                self.bump();
+                break;
+            }
+            if !self.at_kind(SyntaxKind::Ident) {
+                break;
+            }
+            self.start_node(SyntaxKind::Formal);
+            self.bump();
+            if self.at("?") {
+                self.bump();
+                self.expr();
+            }
+            self.finish_node();
+            if !self.at(",") {
+                break;
+            }
+            self.bump();
+        }
+        self.expect("}");
+        self.finish_node();
+    }
+
+    fn binary_op(&self, min_prec: u8) -> Option<(u8, Assoc)> {
+        let token = self.nth(0)?;
+        BINARY_OPS
+            .iter()
+            .find(|(op, prec, _)| *prec >= min_prec && token.is(op))
+            .map(|&(_, prec, assoc)| (prec, assoc))
+    }
+
+    fn expr_op(&mut self, min_prec: u8) {
+        let checkpoint = self.checkpoint();
+        if self.at("!") || self.at("-") {
+            let prec = if self.at("!") {
+                NOT_PREC + 1
+            } else {
+                NEGATE_PREC
+            };
+            self.start_node(SyntaxKind::UnaryOp);
+            self.bump();
+            self.expr_op(prec);
+            self.finish_node();
+        } else {
+            self.expr_app();
+        }
+
+        let mut last_nonassoc = None;
+        while let Some((prec, assoc)) = self.binary_op(min_prec) {
+            if last_nonassoc == Some(prec) {
+                break;
+            }
+            if assoc == Assoc::None {
+                last_nonassoc = Some(prec);
+            }
+            if self.at("?") {
+                self.start_node_at(checkpoint, SyntaxKind::HasAttr);
+                self.bump();
+                self.attrpath();
+            } else {
+                self.start_node_at(checkpoint, SyntaxKind::BinOp);
+                self.bump();
+                self.expr_op(if assoc == Assoc::Right {
+                    prec
+                } else {
+                    prec + 1
+                });
+            }
+            self.finish_node();
+        }
+    }
+
+    /// Whether the next token starts an argument in a function application
+    /// or an element of a list.
+    fn at_simple(&self) -> bool {
+        let token = match self.nth(0) {
+            Some(token) => token,
+            None => return false,
+        };
+        match token.kind {
+            SyntaxKind::Ident
+            | SyntaxKind::Int
+            | SyntaxKind::Float
+            | SyntaxKind::PathContent
+            | SyntaxKind::SearchPath
+            | SyntaxKind::Uri => true,
+            SyntaxKind::Keyword => {
+                (token.text == "let" || token.text == "rec") && self.nth_is(1, "{")
+            }
+            SyntaxKind::Punct => ["\"", "''", "(", "{", "["].contains(&token.text.as_str()),
+            _ => false,
+        }
+    }
+
+    fn expr_app(&mut self) {
+        let checkpoint = self.checkpoint();
+        self.expr_select();
+        while self.at_simple() {
+            self.start_node_at(checkpoint, SyntaxKind::Apply);
+            self.expr_select();
+            self.finish_node();
+        }
+    }
+
+    fn expr_select(&mut self) {
+        let checkpoint = self.checkpoint();
+        self.expr_simple();
+        if self.at(".") {
+            self.start_node_at(checkpoint, SyntaxKind::Select);
+            self.bump();
+            self.attrpath();
+            if self.at("or") {
+                self.bump();
+                self.expr_select();
+            }
+            self.finish_node();
+        } else if self.at("or") {
+            // The legacy `f or`, applying `f` to a variable called `or`.
+            self.start_node_at(checkpoint, SyntaxKind::Apply);
+            self.start_node(SyntaxKind::Var);
+            self.bump();
+            self.finish_node();
+            self.finish_node();
+        }
+    }
+
+    fn expr_simple(&mut self) {
+        let token = match self.nth(0) {
+            Some(token) => token.clone(),
+            None => return self.missing(),
+        };
+        match token.kind {
+            SyntaxKind::Ident => {
+                self.start_node(SyntaxKind::Var);
+                self.bump();
+                self.finish_node();
+            }
+            SyntaxKind::Int | SyntaxKind::Float | SyntaxKind::SearchPath | SyntaxKind::Uri => {
+                self.start_node(SyntaxKind::Literal);
+                self.bump();
+                self.finish_node();
+            }
+            SyntaxKind::PathContent => {
+                self.start_node(SyntaxKind::Path);
+                self.bump();
+                // Path mode only lexes pieces directly after the path.
+                while let Some(kind) = self.current().map(SyntaxToken::kind) {
+                    match kind {
+                        SyntaxKind::PathContent => self.bump(),
+                        SyntaxKind::InterpolStart => self.interpol(),
+                        _ => break,
+                    }
+                }
+                self.finish_node();
+            }
+            _ if token.is("\"") || token.is("''") => {
+                let (kind, close) = if token.is("\"") {
+                    (SyntaxKind::String, "\"")
+                } else {
+                    (SyntaxKind::IndString, "''")
+                };
+                self.start_node(kind);
+                self.bump();
+                loop {
+                    match self.current().cloned() {
+                        Some(token) if token.kind == SyntaxKind::StringContent => self.bump(),
+                        Some(token) if token.kind == SyntaxKind::InterpolStart => self.interpol(),
+                        Some(token) if token.is(close) => {
+                            self.bump();
+                            break;
+                        }
+                        _ => {
+                            self.missing();
+                            break;
+                        }
+                    }
+                }
+                self.finish_node();
+            }
+            _ if token.is("(") => {
+                self.start_node(SyntaxKind::Paren);
+                self.bump();
+                self.expr();
+                self.expect(")");
+                self.finish_node();
+            }
+            _ if token.is("let") => {
+                self.start_node(SyntaxKind::LegacyLet);
+                self.bump();
+                self.expect("{");
+                self.binds();
+                self.expect("}");
+                self.finish_node();
+            }
+            _ if token.is("rec") || token.is("{") => {
+                self.start_node(SyntaxKind::AttrSet);
+                if token.is("rec") {
+                    self.bump();
+                }
+                self.expect("{");
+                self.binds();
+                self.expect("}");
+                self.finish_node();
+            }
+            _ if token.is("[") => {
+                self.start_node(SyntaxKind::List);
+                self.bump();
+                while self.at_simple() {
+                    self.expr_select();
+                }
+                self.expect("]");
+                self.finish_node();
+            }
+            // Leave closing tokens for whatever they close.
+            _ if [")", "]", "}", ";", ",", ":", "in", "then", "else"]
+                .iter()
+                .any(|t| token.is(t)) =>
+            {
+                self.missing()
+            }
+            _ => {
+                self.start_node(SyntaxKind::Error);
+                self.bump();
+                self.finish_node();
+            }
+        }
+    }
+
+    fn interpol(&mut self) {
+        self.start_node(SyntaxKind::Interpol);
+        self.bump();
+        self.expr();
+        self.expect_kind(SyntaxKind::InterpolEnd);
+        self.finish_node();
+    }
+
+    fn at_attr(&self) -> bool {
+        self.at_kind(SyntaxKind::Ident)
+            || self.at("or")
+            || self.at("\"")
+            || self.at_kind(SyntaxKind::InterpolStart)
+    }
+
+    fn attr(&mut self) {
+        if self.at("\"") {
+            self.expr_simple();
+        } else if self.at_kind(SyntaxKind::InterpolStart) {
+            self.interpol();
+        } else if self.at_attr() {
+            self.bump();
+        } else {
+            self.missing();
+        }
+    }
+
+    fn attrpath(&mut self) {
+        self.start_node(SyntaxKind::AttrPath);
+        self.attr();
+        while self.at(".") {
+            self.bump();
+            self.attr();
+        }
+        self.finish_node();
+    }
+
+    fn binds(&mut self) {
+        loop {
+            if self.nth(0).is_none()
+                || self.at("}")
+                || self.at("in")
+                || self.at_kind(SyntaxKind::InterpolEnd)
+            {
+                break;
+            }
+            if self.at("inherit") {
+                self.inherit();
+            } else if self.at_attr() {
+                self.start_node(SyntaxKind::Binding);
+                self.attrpath();
+                self.expect("=");
+                self.expr();
+                self.expect(";");
+                self.finish_node();
+            } else {
+                self.skip_binding();
+            }
+        }
+    }
+
+    fn inherit(&mut self) {
+        self.start_node(SyntaxKind::Inherit);
+        self.bump();
+        if self.at("(") {
+            self.start_node(SyntaxKind::InheritFrom);
+            self.bump();
+            self.expr();
+            self.expect(")");
+            self.finish_node();
+        }
+        while self.at_attr() {
+            self.attr();
+        }
+        self.expect(";");
+        self.finish_node();
+    }
+
+    /// Wrap everything up to the end of a broken binding in an `Error` node.
+    fn skip_binding(&mut self) {
+        self.start_node(SyntaxKind::Error);
+        let mut depth = 0_usize;
+        while let Some(token) = self.nth(0) {
+            // `}` also matches the end of an antiquotation.
+            if depth == 0 && (token.is("}") || token.is("in")) {
+                break;
+            }
+            let ends = depth == 0 && token.is(";");
+            if ["(", "[", "{", "${"].iter().any(|t| token.is(t)) {
+                depth += 1;
+            } else if [")", "]", "}"].iter().any(|t| token.is(t)) {
+                depth = depth.saturating_sub(1);
+            }
+            self.bump();
+            if ends {
+                break;
+            }
+        }
+        self.finish_node();
+    }
+}
+
+/// Lowers a syntax tree into an [`Expr`], desugaring it the same way as
+/// [`crate::parser`].
+struct Lower {
+    file: Rc<str>,
+    /// The directory relative paths are resolved against.
+    base_path: PathBuf,
+    /// Where each node and token starts, keyed by address, since the tree
+    /// doesn't store offsets.
+    node_pos: HashMap<*const SyntaxNode, Pos>,
+    token_pos: HashMap<*const SyntaxToken, Pos>,
+}
+
+impl Lower {
+    fn record_positions(&mut self, node: &SyntaxNode, cursor: &mut (usize, usize)) {
+        let file = self.file.clone();
+        let pos = |&mut (line, column): &mut (usize, usize)| {
+            Pos::Known(KnownPos {
+                file: file.clone(),
+                line,
+                column,
+            })
+        };
+        self.node_pos.insert(node, pos(cursor));
+        for child in &node.children {
+            match child {
+                SyntaxElement::Node(child) => self.record_positions(child, cursor),
+                SyntaxElement::Token(token) => {
+                    self.token_pos.insert(token, pos(cursor));
+                    for c in token.text.chars() {
+                        if c == '\n' {
+                            *cursor = (cursor.0 + 1, 1);
+                        } else {
+                            cursor.1 += 1;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn pos(&self, node: &SyntaxNode) -> Pos {
+        self.node_pos
+            .get(&(node as *const _))
+            .cloned()
+            .unwrap_or(Pos::Undefined)
+    }
+
+    fn token_pos(&self, token: &SyntaxToken) -> Pos {
+        self.token_pos
+            .get(&(token as *const _))
+            .cloned()
+            .unwrap_or(Pos::Undefined)
+    }
+
+    fn error(&self, node: &SyntaxNode) -> NixError {
+        let msg = match node.tokens().first() {
+            Some(token) => format!("syntax error, unexpected '{}'", token.text),
+            None => "syntax error, something is missing here".to_owned(),
+        };
+        NixError::Parse(msg, self.pos(node).to_owned())
+    }
+
+    /// For trees which don't have the shape the CST parser gives them,
+    /// which can only happen if they've been edited.
+    fn malformed(&self, node: &SyntaxNode) -> NixError {
+        NixError::Parse(
+            format!("malformed {:?} node", node.kind),
+            self.pos(node).to_owned(),
+        )
+    }
+
+    fn nth_node<'n>(&self, node: &'n SyntaxNode, n: usize) -> NixResult<&'n SyntaxNode> {
+        node.child_nodes()
+            .nth(n)
+            .ok_or_else(|| self.malformed(node))
+    }
+
+    fn nth_expr(&self, node: &SyntaxNode, n: usize) -> NixResult<Expr> {
+        self.expr(self.nth_node(node, n)?)
+    }
+
+    fn first_token<'n>(&self, node: &'n SyntaxNode) -> NixResult<&'n SyntaxToken> {
+        node.child_tokens()
+            .next()
+            .ok_or_else(|| self.malformed(node))
+    }
+
+    fn expr(&self, node: &SyntaxNode) -> NixResult<Expr> {
+        let pos = self.pos(node);
+        let expr = match node.kind {
+            SyntaxKind::Paren | SyntaxKind::Interpol => self.nth_expr(node, 0)?,
+            SyntaxKind::Var => match self.first_token(node)?.text.as_str() {
+                "__curPos" => Expr::Pos(pos),
+                name => var(pos, name),
+            },
+            SyntaxKind::Literal => self.literal(node, pos)?,
+            SyntaxKind::Path => self.path(node, pos)?,
+            SyntaxKind::String => self.string(node, pos)?,
+            SyntaxKind::IndString => self.ind_string(node, pos)?,
+            SyntaxKind::List => Expr::List(
+                node.child_nodes()
+                    .map(|elem| self.expr(elem))
+                    .collect::<NixResult<_>>()?,
+            ),
+            SyntaxKind::AttrSet => {
+                let mut attrs = self.binds(node.child_nodes())?;
+                attrs.recursive = node.has_token("rec");
+                Expr::Attrs(attrs)
+            }
+            // `let { ...; body = ...; }` is `rec { ... }.body`.
+            SyntaxKind::LegacyLet => {
+                let mut attrs = self.binds(node.child_nodes())?;
+                attrs.recursive = true;
+                Expr::Select(ExprSelect {
+                    pos: Pos::Undefined,
+                    expr: Box::new(Expr::Attrs(attrs)),
+                    def: None,
+                    attr_path: vec![AttrName::Static(Symbol::new("body"))],
+                })
+            }
+            SyntaxKind::Select => Expr::Select(ExprSelect {
+                pos,
+                expr: Box::new(self.nth_expr(node, 0)?),
+                def: match node.child_nodes().nth(2) {
+                    Some(def) => Some(Box::new(self.expr(def)?)),
+                    None => None,
+                },
+                attr_path: self.attrpath(self.nth_node(node, 1)?)?,
+            }),
+            SyntaxKind::HasAttr => Expr::OpHasAttr(ExprOpHasAttr {
+                expr: Box::new(self.nth_expr(node, 0)?),
+                attr_path: self.attrpath(self.nth_node(node, 1)?)?,
+            }),
+            SyntaxKind::Apply => Expr::BinOp(BinOp::new(
+                OpKind::App,
+                pos,
+                self.nth_expr(node, 0)?,
+                self.nth_expr(node, 1)?,
+            )),
+            SyntaxKind::UnaryOp => {
+                let operand = self.nth_node(node, 0)?;
+                let e = self.expr(operand)?;
+                if node.has_token("!") {
+                    not(self.pos(operand), e)
+                } else {
+                    call_builtin("__sub", pos, Expr::Int(0), e)
+                }
+            }
+            SyntaxKind::BinOp => binary_op_expr(
+                &self.first_token(node)?.text,
+                pos,
+                self.nth_expr(node, 0)?,
+                self.nth_expr(node, 1)?,
+            ),
+            SyntaxKind::If => Expr::If(ExprIf {
+                pos: self.pos(self.nth_node(node, 0)?),
+                cond: Box::new(self.nth_expr(node, 0)?),
+                then: Box::new(self.nth_expr(node, 1)?),
+                else_: Box::new(self.nth_expr(node, 2)?),
+            }),
+            SyntaxKind::Assert => {
+                let cond = self.nth_node(node, 0)?;
+                Expr::Assert(ExprAssert {
+                    pos,
+                    cond: Box::new(self.expr(cond)?),
+                    cond_source: cond.to_string(),
+                    body: Box::new(self.nth_expr(node, 1)?),
+                })
+            }
+            SyntaxKind::With => Expr::With(ExprWith {
+                pos,
+                attrs: Box::new(self.nth_expr(node, 0)?),
+                body: Box::new(self.nth_expr(node, 1)?),
+                prev_with: Level(0),
+            }),
+            SyntaxKind::LetIn => {
+                let nodes: Vec<_> = node.child_nodes().collect();
+                let (body, binds) = nodes.split_last().ok_or_else(|| self.malformed(node))?;
+                let mut attrs = self.binds(binds.iter().copied())?;
+                if let Some(dynamic) = attrs.dynamic_attrs.first() {
+                    return Err(NixError::Parse(
+                        "dynamic attributes not allowed in let".to_owned(),
+                        dynamic.pos.to_owned(),
+                    ));
+                }
+                attrs.recursive = true;
+                Expr::Let(ExprLet {
+                    attrs: Box::new(attrs),
+                    body: Box::new(self.expr(body)?),
+                })
+            }
+            SyntaxKind::Lambda => self.lambda(node, pos)?,
+            SyntaxKind::Error => return Err(self.error(node)),
+            _ => return Err(self.malformed(node)),
+        };
+        Ok(expr)
+    }
+
+    /// An integer, float, search path or URI.
+    fn literal(&self, node: &SyntaxNode, pos: Pos) -> NixResult<Expr> {
+        let token = self.first_token(node)?;
+        let text = token.text.as_str();
+        let invalid =
+            |what| NixError::Parse(format!("invalid {} '{}'", what, text), pos.to_owned());
+        Ok(match token.kind {
+            SyntaxKind::Int => Expr::Int(text.parse().map_err(|_| invalid("integer"))?),
+            SyntaxKind::Float => Expr::Float(NixFloat::from(
+                text.parse::<f64>().map_err(|_| invalid("float"))?,
+            )),
+            // `<nixpkgs/lib>` is `__findFile __nixPath "nixpkgs/lib"`.
+            SyntaxKind::SearchPath => {
+                let path = Expr::String(text[1..text.len() - 1].to_owned());
+                call_builtin("__findFile", pos.clone(), var(pos, "__nixPath"), path)
+            }
+            SyntaxKind::Uri => Expr::String(text.to_owned()),
+            _ => return Err(self.malformed(node)),
+        })
+    }
+
+    fn path(&self, node: &SyntaxNode, pos: Pos) -> NixResult<Expr> {
+        let first = self.first_token(node)?;
+        let path = resolve_path(&first.text, &self.base_path)
+            .map_err(|msg| NixError::Parse(msg, pos.to_owned()))?;
+
+        let mut parts = Vec::new();
+        let mut trailing_slash = first.text.ends_with('/');
+        for child in &node.children[1..] {
+            match child {
+                SyntaxElement::Token(token) => {
+                    parts.push(Expr::String(token.text.clone()));
+                    trailing_slash = token.text.ends_with('/');
+                }
+                SyntaxElement::Node(interpol) => {
+                    parts.push(self.expr(interpol)?);
+                    trailing_slash = false;
+                }
+            }
+        }
+
+        if trailing_slash {
+            return Err(NixError::Parse(
+                "path has a trailing slash".to_owned(),
+                pos.to_owned(),
+            ));
+        }
+        if parts.is_empty() {
+            return Ok(Expr::Path(PathBuf::from(path)));
+        }
+        parts.insert(0, Expr::Path(PathBuf::from(path)));
+        Ok(Expr::ConcatStrings(ExprConcatStrings {
+            pos,
+            force_string: false,
+            exprs: parts,
+        }))
+    }
+
+    /// The text and antiquotations of a string, without its delimiters.
+    fn string_parts<'n>(&self, node: &'n SyntaxNode) -> NixResult<&'n [SyntaxElement]> {
+        match node.children.as_slice() {
+            [_, parts @ .., _] => Ok(parts),
+            _ => Err(self.malformed(node)),
+        }
+    }
+
+    fn string(&self, node: &SyntaxNode, pos: Pos) -> NixResult<Expr> {
+        let mut parts = Vec::new();
+        let mut interpolated = false;
+        let mut s = String::new();
+        for child in self.string_parts(node)? {
+            match child {
+                SyntaxElement::Token(token) => {
+                    unescape_string(&token.text, &mut s);
+                }
+                SyntaxElement::Node(interpol) => {
+                    if !s.is_empty() {
+                        parts.push(Expr::String(std::mem::take(&mut s)));
+                    }
+                    parts.push(self.expr(interpol)?);
+                    interpolated = true;
+                }
+            }
+        }
+
+        if !interpolated {
+            return Ok(Expr::String(s));
+        }
+        if !s.is_empty() {
+            parts.push(Expr::String(s));
+        }
+        Ok(Expr::ConcatStrings(ExprConcatStrings {
+            pos,
+            force_string: true,
+            exprs: parts,
+        }))
+    }
+
+    fn ind_string(&self, node: &SyntaxNode, pos: Pos) -> NixResult<Expr> {
+        let mut parts = Vec::new();
+        for (n, child) in self.string_parts(node)?.iter().enumerate() {
+            match child {
+                SyntaxElement::Token(token) if n == 0 => {
+                    ind_string_parts(skip_ind_string_start(&token.text), &mut parts);
+                }
+                SyntaxElement::Token(token) => {
+                    ind_string_parts(&token.text, &mut parts);
+                }
+                SyntaxElement::Node(interpol) => {
+                    parts.push(IndStringPart::Antiquote(self.expr(interpol)?));
+                }
+            }
+        }
+        Ok(strip_indentation(pos, parts))
+    }
+
+    /// The bindings of an attribute set or `let`.
+    fn binds<'n>(&self, nodes: impl Iterator<Item = &'n SyntaxNode>) -> NixResult<ExprAttrs> {
+        let mut attrs = ExprAttrs::default();
+        for node in nodes {
+            match node.kind {
+                SyntaxKind::Binding => {
+                    let path = self.attrpath(self.nth_node(node, 0)?)?;
+                    attrs.add_attr(path, self.nth_expr(node, 1)?, self.pos(node))?;
+                }
+                SyntaxKind::Inherit => self.inherit(node, &mut attrs)?,
+                _ => return Err(self.malformed(node)),
+            }
+        }
+        Ok(attrs)
+    }
+
+    fn inherit(&self, node: &SyntaxNode, attrs: &mut ExprAttrs) -> NixResult<()> {
+        let mut from = None;
+        for child in &node.children {
+            let (pos, name) = match child {
+                SyntaxElement::Node(from_node) if from_node.kind == SyntaxKind::InheritFrom => {
+                    from = Some(self.nth_expr(from_node, 0)?);
+                    continue;
+                }
+                SyntaxElement::Node(attr) => (self.pos(attr), self.attr_node(attr)?),
+                SyntaxElement::Token(token)
+                    if token.kind == SyntaxKind::Ident || token.is("or") =>
+                {
+                    (
+                        self.token_pos(token),
+                        AttrName::Static(Symbol::new(&token.text)),
+                    )
+                }
+                // `inherit`, `;` and trivia.
+                SyntaxElement::Token(_) => continue,
+            };
+            match name {
+                AttrName::Static(name) => attrs.add_inherited(name, from.as_ref(), pos)?,
+                AttrName::Dynamic(_) => {
+                    return Err(NixError::Parse(
+                        "dynamic attributes not allowed in inherit".to_owned(),
+                        pos.to_owned(),
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn attrpath(&self, node: &SyntaxNode) -> NixResult<AttrPath> {
+        node.children
+            .iter()
+            .filter_map(|child| match child {
+                SyntaxElement::Node(attr) => Some(self.attr_node(attr)),
+                SyntaxElement::Token(token)
+                    if token.kind == SyntaxKind::Ident || token.is("or") =>
+                {
+                    Some(Ok(AttrName::Static(Symbol::new(&token.text))))
+                }
+                // `.` and trivia.
+                SyntaxElement::Token(_) => None,
+            })
+            .collect()
+    }
+
+    /// A string or `${expr}` used as an attribute name.
+    fn attr_node(&self, node: &SyntaxNode) -> NixResult<AttrName> {
+        Ok(match self.expr(node)? {
+            Expr::String(name) if node.kind == SyntaxKind::String => {
+                AttrName::Static(Symbol::new(&name))
+            }
+            name => AttrName::Dynamic(Box::new(name)),
+        })
+    }
+
+    fn lambda(&self, node: &SyntaxNode, pos: Pos) -> NixResult<Expr> {
+        let formals = match node.child_nodes().find(|n| n.kind == SyntaxKind::Formals) {
+            Some(formals) => Some(self.formals(formals)?),
+            None => None,
+        };
+        let arg = node
+            .child_tokens()
+            .find(|token| token.kind == SyntaxKind::Ident)
+            .map(|token| Symbol::new(&token.text));
+        if let (Some(arg), Some(formals)) = (arg, &formals) {
+            if formals.has(arg) {
+                return Err(NixError::Parse(
+                    format!("duplicate formal function argument '{}'", arg),
+                    pos.to_owned(),
+                ));
+            }
+        }
+        let body = node
+            .child_nodes()
+            .last()
+            .ok_or_else(|| self.malformed(node))?;
+        Ok(Expr::Lambda(ExprLambda {
+            pos,
+            name: Cell::new(None),
+            arg,
+            formals,
+            body: Box::new(self.expr(body)?),
+        }))
+    }
+
+    fn formals(&self, node: &SyntaxNode) -> NixResult<Formals> {
+        let mut formals = Formals {
+            formals: Vec::new(),
+            ellipsis: node.has_token("..."),
+        };
+        for formal in node.child_nodes() {
+            let name = Symbol::new(&self.first_token(formal)?.text);
+            if formals.has(name) {
+                return Err(NixError::Parse(
+                    format!("duplicate formal function argument '{}'", name),
+                    self.pos(node).to_owned(),
+                ));
+            }
+            let def = match formal.child_nodes().next() {
+                Some(def) => Some(Box::new(self.expr(def)?)),
+                None => None,
+            };
+            formals.formals.push(Formal { name, def });
+        }
+        Ok(formals)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    const SOURCE: &str = indoc!(
+        r#"
+        # A comment.
+        { pkgs ? import <nixpkgs> {}, ... }@args:
+        let
+          /* Another one. */
+          inherit (pkgs) lib;
+          name = "hello-${args.version or "1.0"}";
+          x.y = ./a/${name}.nix;
+        in rec {
+          script = ''
+            echo ${name} ''${not} '''
+          '';
+          n = -1.5e3 + 2 * (3 - 4) / 5;
+          ok = !(a == b) && c || d -> e // f ++ [ g h ];
+          z = with lib; if a ? b.c then assert x < y; x.${"y"} else https://x.org;
+        }
+        "#
+    );
+
+    #[test]
+    fn round_trip() {
+        assert_eq!(parse(SOURCE).to_string(), SOURCE);
+        assert!(!parse(SOURCE).has_errors());
+    }
+
+    #[test]
+    fn round_trip_broken_code() {
+        for source in &[
+            "{ a = ; b = 2 }",
+            "(1 + )",
+            "let in",
+            "''abc",
+            "[ 1 ) ]",
+            ")",
+        ] {
+            let tree = parse(source);
+            assert_eq!(tree.to_string(), *source);
+            assert!(tree.has_errors(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn trivia() {
+        let tree = parse("# c\n1 /* d */");
+        let kinds: Vec<_> = tree.tokens().iter().map(|token| token.kind()).collect();
+        assert_eq!(
+            kinds,
+            [
+                SyntaxKind::Comment,
+                SyntaxKind::Whitespace,
+                SyntaxKind::Int,
+                SyntaxKind::Whitespace,
+                SyntaxKind::Comment,
+            ]
+        );
+    }
+
+    #[test]
+    fn lower_like_parser() {
+        let base_path = Path::new("/base/dir");
+        let lowered = parse(SOURCE).to_expr("test.nix", base_path).unwrap();
+        let parsed = parser::parse(SOURCE, "test.nix", base_path).unwrap();
+        assert_eq!(lowered, parsed);
+    }
+
+    #[test]
+    fn lower_errors() {
+        let lower = |source| {
+            parse(source)
+                .to_expr("test.nix", Path::new("/"))
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            lower("{ a = 1; a = 2; }"),
+            "attribute 'a' at test.nix:1:10 already defined at test.nix:1:3"
+        );
+        assert_eq!(
+            lower(""),
+            "syntax error, unexpected end of file, at test.nix:1:1"
+        );
+    }
+
+    #[test]
+    fn lower_errors_like_parser() {
+        for source in &[
+            "(1 + )",
+            ")",
+            "1 )",
+            "{ a = ; }",
+            "let a = 1 in a",
+            "if a then b",
+        ] {
+            let tree = parse(source);
+            assert!(tree.has_errors(), "{}", source);
+            let lowered = tree.to_expr("test.nix", Path::new("/")).unwrap_err();
+            let parsed = parser::parse(source, "test.nix", Path::new("/")).unwrap_err();
+            assert_eq!(lowered.to_string(), parsed.to_string(), "{}", source);
+        }
+        assert_eq!(
+            parse("(1 + )")
+                .to_expr("test.nix", Path::new("/"))
+                .unwrap_err()
+                .to_string(),
+            "syntax error, expected expression, found ')', at test.nix:1:6"
+        );
+    }
+}
@@ -1,11 +1,10 @@
-use std::borrow::Borrow;
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::convert::{TryFrom, TryInto};
 
 use derive_more::{Add, AddAssign, From, Into};
 
 use crate::attr_set::Bindings;
-use crate::nix_expr::{Expr, ExprAttrs};
+use crate::nix_expr::Expr;
 use crate::{Symbol, Value};
 
 /// A variable's environment level.
@@ -16,40 +15,61 @@ pub struct Level(pub usize);
 #[derive(Debug, PartialEq, Copy, Clone, From, Into, Add, AddAssign)]
 pub struct Displ(pub usize);
 
+/// A scope at runtime. Environments live in the arena and are shared by
+/// reference between the closures and thunks created in them.
 #[derive(Debug, PartialEq)]
 pub struct Env<'arena> {
-    pub up: Option<Box<Env<'arena>>>,
+    pub up: Option<&'arena Env<'arena>>,
+    /// For `with` environments, how many levels up the next enclosing `with`
+    /// is, or 0 if there isn't one.
     pub prev_with: Level,
-    pub values: EnvInner<'arena>,
+    /// A `with` replaces its expression with the attributes it evaluates to
+    /// the first time a variable is looked up in it, like upstream.
+    pub values: RefCell<EnvInner<'arena>>,
 }
 
-#[derive(Debug, PartialEq)]
+impl<'arena> Env<'arena> {
+    pub fn new(
+        up: Option<&'arena Env<'arena>>,
+        prev_with: Level,
+        values: EnvInner<'arena>,
+    ) -> Self {
+        Env {
+            up,
+            prev_with,
+            values: RefCell::new(values),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum EnvInner<'arena> {
     Plain(Vec<Value<'arena>>),
-    HasWithExpr(Box<ExprAttrs<'arena>>),
+    /// A `with` whose attribute set hasn't been evaluated yet.
+    HasWithExpr(&'arena Expr),
     HasWithAttrs(Bindings<'arena>),
 }
 
-impl<'arena> IntoIterator for Env<'arena> {
-    type Item = EnvLevel<'arena>;
-    type IntoIter = EnvIter<'arena>;
+impl<'a, 'arena> IntoIterator for &'a Env<'arena> {
+    type Item = EnvLevel<'a, 'arena>;
+    type IntoIter = EnvIter<'a, 'arena>;
     fn into_iter(self) -> Self::IntoIter {
-        EnvIter::new(Box::new(self))
+        EnvIter::new(self)
     }
 }
 
-pub struct EnvLevel<'arena> {
-    pub env: &'arena Env<'arena>,
+pub struct EnvLevel<'a, 'arena> {
+    pub env: &'a Env<'arena>,
     pub level: Level,
 }
 
-pub struct EnvIter<'arena> {
-    cur_env: Option<Box<Env<'arena>>>,
+pub struct EnvIter<'a, 'arena> {
+    cur_env: Option<&'a Env<'arena>>,
     level: Level,
 }
 
-impl<'arena> EnvIter<'arena> {
-    fn new(env: Box<Env<'arena>>) -> Self {
+impl<'a, 'arena> EnvIter<'a, 'arena> {
+    fn new(env: &'a Env<'arena>) -> Self {
         EnvIter {
             cur_env: Some(env),
             level: Level(0),
@@ -57,13 +77,13 @@ impl<'arena> EnvIter<'arena> {
     }
 }
 
-impl<'arena> Iterator for EnvIter<'arena> {
-    type Item = EnvLevel<'arena>;
+impl<'a, 'arena> Iterator for EnvIter<'a, 'arena> {
+    type Item = EnvLevel<'a, 'arena>;
     fn next(&mut self) -> Option<Self::Item> {
         // If the current env is None, return early.
         let env = self.cur_env?;
         let ret = Some(EnvLevel {
-            env: env.borrow(),
+            env,
             level: self.level,
         });
 
@@ -74,36 +94,46 @@ impl<'arena> Iterator for EnvIter<'arena> {
     }
 }
 
-pub type Vars<'arena> = HashMap<Symbol<'arena>, Displ>;
+pub type Vars = HashMap<Symbol, Displ>;
 
 pub struct StaticEnv<'arena> {
     pub is_with: bool,
-    pub up: Option<Box<StaticEnv<'arena>>>,
-    pub vars: Vars<'arena>,
+    pub up: Option<&'arena StaticEnv<'arena>>,
+    pub vars: Vars,
 }
 
-impl<'arena> IntoIterator for StaticEnv<'arena> {
+impl<'arena> StaticEnv<'arena> {
+    pub fn new(is_with: bool, up: Option<&'arena StaticEnv<'arena>>) -> Self {
+        StaticEnv {
+            is_with,
+            up,
+            vars: Vars::new(),
+        }
+    }
+}
+
+impl<'arena> IntoIterator for &'arena StaticEnv<'arena> {
     type Item = StaticEnvLevel<'arena>;
     type IntoIter = StaticEnvIter<'arena>;
     fn into_iter(self) -> Self::IntoIter {
-        StaticEnvIter::new(Box::new(self))
+        StaticEnvIter::new(self)
     }
 }
 
 pub struct StaticEnvLevel<'arena> {
-    pub env: Box<StaticEnv<'arena>>,
+    pub env: &'arena StaticEnv<'arena>,
     pub level: Level,
     pub with_level: Option<Level>,
 }
 
 pub struct StaticEnvIter<'arena> {
-    cur_env: Option<Box<StaticEnv<'arena>>>,
+    cur_env: Option<&'arena StaticEnv<'arena>>,
     level: Level,
     with_level: Option<Level>,
 }
 
 impl<'arena> StaticEnvIter<'arena> {
-    fn new(env: Box<StaticEnv<'arena>>) -> Self {
+    fn new(env: &'arena StaticEnv<'arena>) -> Self {
         StaticEnvIter {
             cur_env: Some(env),
             level: Level(0),
@@ -134,3 +164,79 @@ impl<'arena> Iterator for StaticEnvIter<'arena> {
         ret
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arena::Arena;
+    use crate::eval::tests::eval_list;
+    use crate::eval::EvalState;
+    use crate::pos::Pos;
+    use crate::value::{Thunk, ThunkState};
+
+    #[test]
+    fn iteration_borrows() {
+        let arena = Arena::new();
+        let outer = arena.alloc_env(Env::new(None, Level(0), EnvInner::Plain(vec![])));
+        let inner = arena.alloc_env(Env::new(Some(outer), Level(0), EnvInner::Plain(vec![])));
+        // Walking the chain leaves it intact, so it can be walked again.
+        for _ in 0..2 {
+            let levels = inner.into_iter().collect::<Vec<_>>();
+            assert_eq!(levels.len(), 2);
+            assert!(std::ptr::eq(levels[0].env, inner));
+            assert!(std::ptr::eq(levels[1].env, outer));
+            assert_eq!(levels[1].level, Level(1));
+        }
+    }
+
+    #[test]
+    fn closures_share_environments() {
+        let arena = Arena::new();
+        let state = EvalState::new(&arena);
+        let elems = eval_list(&state, "let a = 1; in [ (x: a) (y: a) ]");
+        let envs = elems
+            .into_iter()
+            .map(
+                |elem| match state.force_value(elem, &Pos::Undefined).unwrap() {
+                    Value::Lambda(lambda) => lambda.env,
+                    value => panic!("expected a lambda, got {}", value),
+                },
+            )
+            .collect::<Vec<_>>();
+        assert!(std::ptr::eq(envs[0], envs[1]));
+    }
+
+    /// The environment an unevaluated thunk will be evaluated in.
+    fn thunk_env<'arena>(value: &Value<'arena>) -> &'arena Env<'arena> {
+        match value {
+            Value::Thunk(thunk) => match &*thunk.state() {
+                ThunkState::Thunk(Thunk { env, .. }) => env,
+                state => panic!("expected an unevaluated thunk, got {:?}", state),
+            },
+            value => panic!("expected a thunk, got {}", value),
+        }
+    }
+
+    #[test]
+    fn with_attrs_are_cached() {
+        let arena = Arena::new();
+        let state = EvalState::new(&arena);
+        let elems = eval_list(&state, "with { a = 1; b = 2; }; [ a b ]");
+        let with_env = thunk_env(&elems[1]);
+        assert!(std::ptr::eq(thunk_env(&elems[0]), with_env));
+        assert!(matches!(
+            *with_env.values.borrow(),
+            EnvInner::HasWithExpr(_)
+        ));
+        assert_eq!(
+            state
+                .force_value(elems[0].clone(), &Pos::Undefined)
+                .unwrap(),
+            Value::Int(1)
+        );
+        assert!(matches!(
+            *with_env.values.borrow(),
+            EnvInner::HasWithAttrs(_)
+        ));
+    }
+}
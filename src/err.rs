@@ -11,6 +11,20 @@ pub enum NixError {
     VarLookupUnevaluated(String),
     #[error("type error: {0}")]
     Type(String),
+    #[error("{0}, at {1}")]
+    Parse(String, OwnedPos),
+    #[error("attribute '{0}' at {1} already defined at {2}")]
+    DuplicateAttr(String, OwnedPos, OwnedPos),
+    #[error("dynamic attribute '{0}' at {1} already defined at {2}")]
+    DuplicateDynamicAttr(String, OwnedPos, OwnedPos),
+    #[error("attribute '{0}' missing, at {1}")]
+    MissingAttr(String, OwnedPos),
+    #[error("assertion '{0}' failed at {1}")]
+    AssertionFailed(String, OwnedPos),
+    #[error("infinite recursion encountered, at {0}")]
+    InfiniteRecursion(OwnedPos),
+    #[error("{0}")]
+    Eval(String),
 }
 
 pub type NixResult<T> = Result<T, NixError>;
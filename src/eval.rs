@@ -1,77 +1,108 @@
+use std::cell::{Cell, RefCell};
 use std::collections::{HashMap, HashSet};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
+use crate::arena::Arena;
 use crate::attr_set::Bindings;
-use crate::env::{Env, EnvInner, StaticEnv};
-use crate::nix_expr::{Expr, ExprExt, ExprVar};
+use crate::env::{Displ, Env, EnvInner, Level, StaticEnv};
+use crate::nix_expr::{AttrName, Expr, ExprExt, ExprVar};
+use crate::parser;
 use crate::pos::Pos;
-use crate::symbol_table::{Symbol, SymbolTable};
+use crate::primops::{PrimOp, PrimOpFun};
+use crate::store;
+use crate::symbol_table::Symbol;
+use crate::value::{
+    App, ContextElem, Lambda, NixContext, NixFloat, NixInt, NixString, SharedThunk, Thunk,
+    ThunkState,
+};
 use crate::{NixError, NixResult, Value};
 
-pub type FileParseCache<'arena> = HashMap<PathBuf, Box<Expr<'arena>>>;
+pub type FileParseCache<'arena> = HashMap<PathBuf, &'arena Expr>;
 pub type FileEvalCache<'arena> = HashMap<PathBuf, Value<'arena>>;
-pub type SearchPathElem = (String, String); // ???
+/// An entry of the search path used to resolve `<...>` paths, like
+/// `nixpkgs=/path/to/nixpkgs` from `-I`: a prefix, which is empty if the
+/// entry has no `prefix=`, and the path or URI it maps to.
+pub type SearchPathElem = (String, String);
 pub type SearchPath = Vec<SearchPathElem>;
 
-type ExprLambda = (); // TODO
-
+#[allow(non_snake_case)]
 pub struct EvalState<'arena> {
-    pub symbols: SymbolTable,
-    pub sWith: Symbol<'arena>,
-    pub sOutPath: Symbol<'arena>,
-    pub sDrvPath: Symbol<'arena>,
-    pub sType: Symbol<'arena>,
-    pub sMeta: Symbol<'arena>,
-    pub sName: Symbol<'arena>,
-    pub sValue: Symbol<'arena>,
-    pub sSystem: Symbol<'arena>,
-    pub sOverrides: Symbol<'arena>,
-    pub sOutputs: Symbol<'arena>,
-    pub sOutputName: Symbol<'arena>,
-    pub sIgnoreNulls: Symbol<'arena>,
-    pub sFile: Symbol<'arena>,
-    pub sLine: Symbol<'arena>,
-    pub sColumn: Symbol<'arena>,
-    pub sFunctor: Symbol<'arena>,
-    pub sToString: Symbol<'arena>,
-    pub sRight: Symbol<'arena>,
-    pub sWrong: Symbol<'arena>,
-    pub sStructuredAttrs: Symbol<'arena>,
-    pub sBuilder: Symbol<'arena>,
-    pub sArgs: Symbol<'arena>,
-    pub sOutputHash: Symbol<'arena>,
-    pub sOutputHashAlgo: Symbol<'arena>,
-    pub sOutputHashMode: Symbol<'arena>,
-    pub sDerivationNix: Symbol<'arena>,
+    pub arena: &'arena Arena<'arena>,
+    pub sWith: Symbol,
+    pub sOutPath: Symbol,
+    pub sDrvPath: Symbol,
+    pub sType: Symbol,
+    pub sMeta: Symbol,
+    pub sName: Symbol,
+    pub sValue: Symbol,
+    pub sSystem: Symbol,
+    pub sOverrides: Symbol,
+    pub sOutputs: Symbol,
+    pub sOutputName: Symbol,
+    pub sIgnoreNulls: Symbol,
+    pub sFile: Symbol,
+    pub sLine: Symbol,
+    pub sColumn: Symbol,
+    pub sFunctor: Symbol,
+    pub sToString: Symbol,
+    pub sRight: Symbol,
+    pub sWrong: Symbol,
+    pub sStructuredAttrs: Symbol,
+    pub sBuilder: Symbol,
+    pub sArgs: Symbol,
+    pub sOutputHash: Symbol,
+    pub sOutputHashAlgo: Symbol,
+    pub sOutputHashMode: Symbol,
+    pub sDerivationNix: Symbol,
     /// The allowed filesystem paths in restricted or pure evaluation
     /// mode.
     pub allowed_paths: Option<HashSet<PathBuf>>,
+    /// How deeply functions may call each other, or values may be nested
+    /// when they're deeply forced, before evaluation fails with a stack
+    /// overflow error, instead of overflowing the real stack.
+    /// A call takes a few kilobytes of stack, and several times that in
+    /// debug builds, so this should be lowered when evaluating on a thread
+    /// with a small stack.
+    pub max_call_depth: usize,
     pub empty_set: Value<'arena>,
     // If set, force copying files to the Nix store even if they
     // already exist there.
     // RepairFlag
     // Store
-    // SrcToStore
+    /// How many calls, or levels of `force_value_deep`, are being
+    /// evaluated, for `max_call_depth`.
+    call_depth: Cell<usize>,
+    /// The store paths of sources which have already been coerced to
+    /// strings.
+    src_to_store: RefCell<HashMap<PathBuf, PathBuf>>,
     /// A cache from path names to parse trees.
+    #[allow(dead_code)] // Used once `import` exists.
     file_parse_cache: FileParseCache<'arena>,
 
     /// A cache from path names to values.
+    #[allow(dead_code)]
     file_eval_cache: FileEvalCache<'arena>,
 
+    #[allow(dead_code)]
     search_path: SearchPath,
+    #[allow(dead_code)]
     search_path_resolved: HashMap<String, (bool, String)>,
     /// Cache used by checkSourcePath().
+    #[allow(dead_code)]
     resolved_paths: HashMap<PathBuf, PathBuf>,
     /// Cache used by prim_match().
+    #[allow(dead_code)]
     regex_cache: HashMap<String, ()>,
 
     /// The base environment, containing the builtin functions and
     /// values.
-    pub base_env: Box<Env<'arena>>,
+    pub base_env: &'arena Env<'arena>,
 
     /// The same as `base_env`, but used during parsing to resolve variables.
     static_base_env: StaticEnv<'arena>,
-    base_env_display: usize,
+    /// The values of `base_env` while it's being created.
+    base_env_values: Vec<Value<'arena>>,
     // Statistics tracking...?
     // nr_envs: usize,
     // nr_values_in_envs: usize,
@@ -86,80 +117,1116 @@ pub struct EvalState<'arena> {
     // nr_function_calls: usize,
     // count_calls: bool,
 
-    // primop_calls: HashMap<Symbol<'arena>, usize>,
+    // primop_calls: HashMap<Symbol, usize>,
     // function_calls: HashMap<ExprLambda, usize>,
-    // attr_selects: HashMap<Pos<'arena>, usize>,
+    // attr_selects: HashMap<Pos, usize>,
 }
 
+/// The default `max_call_depth`. Upstream's is 10000, but calls take much
+/// more stack here, so this fits in the 8 MiB of a main thread.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = if cfg!(debug_assertions) { 250 } else { 1000 };
+
 #[derive(Copy, Clone, PartialEq)]
 pub enum ShouldEval {
     Yes,
     No,
 }
 
+fn type_error(value: &Value<'_>, expected: &str, pos: &Pos) -> NixError {
+    match pos {
+        Pos::Undefined => NixError::Type(format!(
+            "value is {} while {} was expected",
+            value.show_type(),
+            expected
+        )),
+        Pos::Known(pos) => NixError::Type(format!(
+            "value is {} while {} was expected, at {}",
+            value.show_type(),
+            expected,
+            pos
+        )),
+    }
+}
+
 impl<'arena> EvalState<'arena> {
+    pub fn new(arena: &'arena Arena<'arena>) -> Self {
+        let mut state = EvalState {
+            arena,
+            sWith: Symbol::WITH,
+            sOutPath: Symbol::OUT_PATH,
+            sDrvPath: Symbol::DRV_PATH,
+            sType: Symbol::TYPE,
+            sMeta: Symbol::META,
+            sName: Symbol::NAME,
+            sValue: Symbol::VALUE,
+            sSystem: Symbol::SYSTEM,
+            sOverrides: Symbol::OVERRIDES,
+            sOutputs: Symbol::OUTPUTS,
+            sOutputName: Symbol::OUTPUT_NAME,
+            sIgnoreNulls: Symbol::IGNORE_NULLS,
+            sFile: Symbol::FILE,
+            sLine: Symbol::LINE,
+            sColumn: Symbol::COLUMN,
+            sFunctor: Symbol::FUNCTOR,
+            sToString: Symbol::TO_STRING,
+            sRight: Symbol::RIGHT,
+            sWrong: Symbol::WRONG,
+            sStructuredAttrs: Symbol::STRUCTURED_ATTRS,
+            sBuilder: Symbol::BUILDER,
+            sArgs: Symbol::ARGS,
+            sOutputHash: Symbol::OUTPUT_HASH,
+            sOutputHashAlgo: Symbol::OUTPUT_HASH_ALGO,
+            sOutputHashMode: Symbol::OUTPUT_HASH_MODE,
+            sDerivationNix: Symbol::DERIVATION_NIX,
+            allowed_paths: None,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            call_depth: Cell::new(0),
+            src_to_store: RefCell::new(HashMap::new()),
+            empty_set: Value::Attrs(Bindings::new()),
+            file_parse_cache: FileParseCache::new(),
+            file_eval_cache: FileEvalCache::new(),
+            search_path: SearchPath::new(),
+            search_path_resolved: HashMap::new(),
+            resolved_paths: HashMap::new(),
+            regex_cache: HashMap::new(),
+            // Replaced once `create_base_env` has added everything.
+            base_env: arena.alloc_env(Env::new(None, Level(0), EnvInner::Plain(Vec::new()))),
+            base_env_values: Vec::new(),
+            static_base_env: StaticEnv::new(false, None),
+        };
+        state.create_base_env();
+        let values = std::mem::take(&mut state.base_env_values);
+        state.base_env = arena.alloc_env(Env::new(None, Level(0), EnvInner::Plain(values)));
+        state
+    }
+
+    /// Add a value to the base environment, and to `builtins` without any
+    /// `__` prefix. This is only possible while `create_base_env` runs.
+    pub(crate) fn add_constant(&mut self, name: &str, value: Value<'arena>) {
+        let sym = Symbol::new(name);
+        let displ = Displ(self.base_env_values.len());
+        self.static_base_env.vars.insert(sym, displ);
+        let name2 = Symbol::new(name.strip_prefix("__").unwrap_or(name));
+        if let Some(Value::Attrs(builtins)) = self.base_env_values.first_mut() {
+            builtins.insert(name2, value.clone(), Pos::Undefined);
+        }
+        self.base_env_values.push(value);
+    }
+
+    pub(crate) fn add_primop(&mut self, name: &str, arity: usize, fun: PrimOpFun) {
+        let name2 = name.strip_prefix("__").unwrap_or(name).to_owned();
+        self.add_constant(
+            name,
+            Value::PrimOp(PrimOp {
+                name: name2,
+                arity,
+                fun,
+            }),
+        );
+    }
+
+    /// Parse an expression and resolve its variables against the base
+    /// environment, ready for evaluation.
+    pub fn parse_expr_from_string(
+        &self,
+        source: &str,
+        base_path: &Path,
+    ) -> NixResult<&'arena Expr> {
+        let mut expr = parser::parse(source, "(string)", base_path)?;
+        expr.bind_vars(&self.static_base_env)?;
+        Ok(self.arena.alloc_expr(expr))
+    }
+
+    /// Evaluate an expression from `parse_expr_from_string` to weak head
+    /// normal form.
+    pub fn eval(&self, expr: &'arena Expr) -> NixResult<Value<'arena>> {
+        expr.eval(self, self.base_env)
+    }
+
+    /// Evaluate a value to weak head normal form. `pos` is reported if this
+    /// turns out to need its own value, like `let x = x; in x`.
+    pub fn force_value(&self, value: Value<'arena>, pos: &Pos) -> NixResult<Value<'arena>> {
+        match value {
+            Value::Thunk(thunk) => {
+                if let ThunkState::Value(value) = &*thunk.state() {
+                    return match value {
+                        Value::Blackhole => Err(NixError::InfiniteRecursion(pos.to_owned())),
+                        value => Ok(value.clone()),
+                    };
+                }
+
+                // Mark the thunk while it's evaluated, so forcing it again
+                // from inside is caught.
+                let pending = thunk.replace_state(ThunkState::Value(Value::Blackhole));
+                let result = match &pending {
+                    ThunkState::Thunk(Thunk { env, expr }) => expr.eval(self, env),
+                    ThunkState::Value(_) => unreachable!(),
+                };
+                match result {
+                    Ok(value) => {
+                        thunk.replace_state(ThunkState::Value(value.clone()));
+                        Ok(value)
+                    }
+                    Err(err) => {
+                        thunk.replace_state(pending);
+                        Err(err)
+                    }
+                }
+            }
+            value => Ok(value),
+        }
+    }
+
+    /// Evaluate a value and everything inside it. The thunks inside are
+    /// overwritten with their values, which is what the result refers to.
+    /// Each thunk is only visited once, so cyclic values like
+    /// `let x = { inherit x; }; in x` are fine.
+    pub fn force_value_deep(&self, value: Value<'arena>) -> NixResult<Value<'arena>> {
+        let value = self.force_value(value, &Pos::Undefined)?;
+        self.force_contents_deep(&value, &mut HashSet::new())?;
+        Ok(value)
+    }
+
+    /// Deeply force the attributes or elements of a forced value, skipping
+    /// the thunks in `seen`.
+    fn force_contents_deep(
+        &self,
+        value: &Value<'arena>,
+        seen: &mut HashSet<*const ()>,
+    ) -> NixResult<()> {
+        let mut force = |value: &Value<'arena>, pos| {
+            if let Value::Thunk(thunk) = value {
+                if !seen.insert(thunk.as_ptr()) {
+                    return Ok(());
+                }
+            }
+            let value = self.force_value(value.clone(), pos)?;
+            self.nested(pos, || self.force_contents_deep(&value, seen))
+        };
+        match value {
+            Value::Attrs(attrs) => attrs
+                .0
+                .values()
+                .try_for_each(|attr| force(&attr.value, &attr.pos)),
+            Value::List(elems) => elems
+                .iter()
+                .try_for_each(|elem| force(elem, &Pos::Undefined)),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn force_int(&self, value: Value<'arena>, pos: &Pos) -> NixResult<NixInt> {
+        match self.force_value(value, pos)? {
+            Value::Int(n) => Ok(n),
+            value => Err(type_error(&value, "an integer", pos)),
+        }
+    }
+
+    pub fn force_float(&self, value: Value<'arena>, pos: &Pos) -> NixResult<NixFloat> {
+        match self.force_value(value, pos)? {
+            Value::Int(n) => Ok((n as f64).into()),
+            Value::Float(n) => Ok(n),
+            value => Err(type_error(&value, "a float", pos)),
+        }
+    }
+
+    pub fn force_bool(&self, value: Value<'arena>, pos: &Pos) -> NixResult<bool> {
+        match self.force_value(value, pos)? {
+            Value::Bool(b) => Ok(b),
+            value => Err(type_error(&value, "a Boolean", pos)),
+        }
+    }
+
+    pub fn force_attrs(&self, value: Value<'arena>, pos: &Pos) -> NixResult<Bindings<'arena>> {
+        match self.force_value(value, pos)? {
+            Value::Attrs(attrs) => Ok(attrs),
+            value => Err(type_error(&value, "a set", pos)),
+        }
+    }
+
+    pub fn force_list(&self, value: Value<'arena>, pos: &Pos) -> NixResult<Rc<[Value<'arena>]>> {
+        match self.force_value(value, pos)? {
+            Value::List(elems) => Ok(elems),
+            value => Err(type_error(&value, "a list", pos)),
+        }
+    }
+
+    /// Whether the value is an attribute set which can be called like a
+    /// function, because it has a `__functor` attribute.
+    pub fn is_functor(&self, value: &Value<'arena>) -> bool {
+        match value {
+            Value::Attrs(attrs) => attrs.get(self.sFunctor).is_some(),
+            _ => false,
+        }
+    }
+
+    pub fn force_function(&self, value: Value<'arena>, pos: &Pos) -> NixResult<Value<'arena>> {
+        let value = self.force_value(value, pos)?;
+        if value.is_function() || self.is_functor(&value) {
+            Ok(value)
+        } else {
+            Err(type_error(&value, "a function", pos))
+        }
+    }
+
+    pub fn force_string(&self, value: Value<'arena>, pos: &Pos) -> NixResult<NixString> {
+        match self.force_value(value, pos)? {
+            Value::String(s) => Ok(s),
+            value => Err(type_error(&value, "a string", pos)),
+        }
+    }
+
+    /// Like `force_string`, but fail if the string refers to store paths.
+    pub fn force_string_no_ctx(&self, value: Value<'arena>, pos: &Pos) -> NixResult<String> {
+        let s = self.force_string(value, pos)?;
+        if let Some(path) = s.context.iter().next() {
+            return Err(NixError::Eval(format!(
+                "the string '{}' is not allowed to refer to a store path (such as '{}'), at {}",
+                s.s, path, pos
+            )));
+        }
+        Ok(s.s)
+    }
+
     pub fn eval_attrs(
         &self,
-        env: &Env<'arena>,
-        expr: &'arena impl ExprExt,
+        env: &'arena Env<'arena>,
+        expr: &'arena Expr,
+        pos: &Pos,
     ) -> NixResult<Bindings<'arena>> {
-        let ret = expr.eval(&self, env)?;
-        if let Value::Attrs(attrs) = ret {
-            Ok(*attrs)
-        } else {
-            Err(NixError::Type(format!(
-                // TODO pretty-print the value
-                "value is {:?} while a set was expected",
-                ret
-            )))
+        let value = expr.eval(self, env)?;
+        self.force_attrs(value, pos)
+    }
+
+    pub fn eval_bool(
+        &self,
+        env: &'arena Env<'arena>,
+        expr: &'arena Expr,
+        pos: &Pos,
+    ) -> NixResult<bool> {
+        let value = expr.eval(self, env)?;
+        self.force_bool(value, pos)
+    }
+
+    /// The name an attribute path component refers to.
+    pub fn get_name(
+        &self,
+        name: &'arena AttrName,
+        env: &'arena Env<'arena>,
+        pos: &Pos,
+    ) -> NixResult<Symbol> {
+        match name {
+            AttrName::Static(name) => Ok(*name),
+            AttrName::Dynamic(expr) => {
+                let value = expr.eval(self, env)?;
+                let name = self.force_string_no_ctx(value, pos)?;
+                Ok(Symbol::new(&name))
+            }
         }
     }
 
-    pub fn lookup_var(
+    /// The `{ file, line, column }` set for `__curPos`, or `null` if the
+    /// position isn't known.
+    pub fn mk_pos(&self, pos: &Pos) -> Value<'arena> {
+        match pos {
+            Pos::Undefined => Value::Null,
+            Pos::Known(pos) => {
+                let mut attrs = Bindings::new();
+                attrs.insert(
+                    self.sFile,
+                    Value::String(NixString::new(pos.file.to_string())),
+                    Pos::Undefined,
+                );
+                attrs.insert(self.sLine, Value::Int(pos.line as NixInt), Pos::Undefined);
+                attrs.insert(
+                    self.sColumn,
+                    Value::Int(pos.column as NixInt),
+                    Pos::Undefined,
+                );
+                Value::Attrs(attrs)
+            }
+        }
+    }
+
+    pub fn lookup_var<'e>(
         &self,
-        env: &Env<'arena>,
-        var: &ExprVar<'arena>,
+        env: &'e Env<'arena>,
+        var: &ExprVar,
         should_eval: ShouldEval,
     ) -> NixResult<Value<'arena>> {
-        let env = env
-            .into_iter()
-            .find(|env_level| env_level.level == var.level)
-            .unwrap()
-            .env;
+        let up = |env: &'e Env<'arena>, level: Level| {
+            env.into_iter()
+                .find(|env_level| env_level.level == level)
+                .expect("variables are bound to an existing level")
+                .env
+        };
+        let mut env = up(env, var.level);
 
         if !var.from_with {
-            match env.values {
-                EnvInner::Plain(values) => return Ok(values[var.displ.0]),
+            return match &*env.values.borrow() {
+                EnvInner::Plain(values) => Ok(values[var.displ.0].clone()),
                 EnvInner::HasWithAttrs(_) | EnvInner::HasWithExpr(_) => unreachable!(),
-            }
+            };
         }
 
+        // Lexical bindings always win, so the variable is in one of the
+        // enclosing `with`s, innermost first.
         loop {
-            if let EnvInner::HasWithExpr(expr) = env.values {
+            // The borrow can't be held while the attributes are evaluated.
+            let with_expr = match &*env.values.borrow() {
+                EnvInner::HasWithExpr(expr) => Some(*expr),
+                EnvInner::HasWithAttrs(_) => None,
+                EnvInner::Plain(_) => unreachable!(),
+            };
+            if let Some(expr) = with_expr {
                 if should_eval == ShouldEval::No {
                     return Err(NixError::VarLookupUnevaluated(var.name.into()));
                 }
-                let value = self.eval_attrs(&env.up.unwrap(), &*expr)?;
-                env.values = EnvInner::HasWithAttrs(value);
+                let up = env.up.expect("`with` has an enclosing scope");
+                let attrs = self.eval_attrs(up, expr, &var.pos)?;
+                env.values.replace(EnvInner::HasWithAttrs(attrs));
+            }
+            if let EnvInner::HasWithAttrs(attrs) = &*env.values.borrow() {
+                if let Some(attr) = attrs.get(var.name) {
+                    return Ok(attr.value.clone());
+                }
+            }
+            if env.prev_with == Level(0) {
+                return Err(NixError::UndefinedVar(var.name.into(), var.pos.to_owned()));
             }
+            env = up(env, env.prev_with);
+        }
+    }
 
-            let first_value = match env.values {
-                EnvInner::Plain(values) => match values[0] {
-                    Value::Attrs(bindings) => bindings,
-                    _ => unreachable!(),
-                },
-                EnvInner::HasWithAttrs(bindings) => bindings,
-                EnvInner::HasWithExpr(_) => unreachable!(),
-            };
-            if let Some((name, attr)) = first_value
-                .0
-                .into_iter()
-                .find(|(name, _)| name == &var.name)
-            {
-                return Ok(attr.value);
+    pub fn call_function(
+        &self,
+        fun: Value<'arena>,
+        arg: Value<'arena>,
+        pos: &Pos,
+    ) -> NixResult<Value<'arena>> {
+        self.nested(pos, || self.call_value(fun, arg, pos))
+    }
+
+    /// Run `f` one level deeper, failing instead if that's deeper than
+    /// `max_call_depth`.
+    fn nested<T>(&self, pos: &Pos, f: impl FnOnce() -> NixResult<T>) -> NixResult<T> {
+        let depth = self.call_depth.get();
+        if depth >= self.max_call_depth {
+            return Err(NixError::Eval(format!(
+                "stack overflow; max-call-depth exceeded, at {}",
+                pos
+            )));
+        }
+        self.call_depth.set(depth + 1);
+        let result = f();
+        self.call_depth.set(depth);
+        result
+    }
+
+    fn call_value(
+        &self,
+        fun: Value<'arena>,
+        arg: Value<'arena>,
+        pos: &Pos,
+    ) -> NixResult<Value<'arena>> {
+        match self.force_value(fun, pos)? {
+            Value::Lambda(lambda) => self.call_lambda(lambda, arg, pos),
+            fun @ Value::PrimOp(_) | fun @ Value::PrimOpApp(_) => self.call_primop(fun, arg, pos),
+            Value::Attrs(attrs) if attrs.get(self.sFunctor).is_some() => {
+                // `f arg` is `f.__functor f arg`.
+                let functor = attrs.get(self.sFunctor).unwrap().value.clone();
+                let fun = self.call_function(functor, Value::Attrs(attrs), pos)?;
+                self.call_function(fun, arg, pos)
             }
+            fun => Err(NixError::Type(format!(
+                "attempt to call something which is not a function but {}, at {}",
+                fun.show_type(),
+                pos
+            ))),
+        }
+    }
+
+    fn call_primop(
+        &self,
+        fun: Value<'arena>,
+        arg: Value<'arena>,
+        pos: &Pos,
+    ) -> NixResult<Value<'arena>> {
+        // Partial applications are chains of `PrimOpApp`s ending in the
+        // primop, with the last argument outermost.
+        let mut args = vec![arg];
+        let mut left = &fun;
+        while let Value::PrimOpApp(app) = left {
+            args.push(app.right.clone());
+            left = &app.left;
         }
+        let primop = match left {
+            Value::PrimOp(primop) => primop,
+            _ => unreachable!(),
+        };
+
+        if args.len() < primop.arity {
+            let right = args.swap_remove(0);
+            return Ok(Value::PrimOpApp(Box::new(App { left: fun, right })));
+        }
+        args.reverse();
+        (primop.fun)(self, pos, &args)
+    }
+
+    fn call_lambda(
+        &self,
+        lambda: Lambda<'arena>,
+        arg: Value<'arena>,
+        pos: &Pos,
+    ) -> NixResult<Value<'arena>> {
+        let Lambda { env: closure, fun } = lambda;
+        let mut values = Vec::new();
+        let mut defaults = Vec::new();
+        match &fun.formals {
+            None => values.push(arg),
+            Some(formals) => {
+                let attrs = self.force_attrs(arg.clone(), pos)?;
+                if fun.arg.is_some() {
+                    values.push(arg);
+                }
+                let mut attrs_used = 0;
+                for formal in &formals.formals {
+                    match (attrs.get(formal.name), &formal.def) {
+                        (Some(attr), _) => {
+                            attrs_used += 1;
+                            values.push(attr.value.clone());
+                        }
+                        // Defaults are evaluated in the function's own
+                        // scope, so they're filled in once it exists.
+                        (None, Some(def)) => {
+                            let thunk = SharedThunk::blackhole(self.arena);
+                            defaults.push((thunk, def));
+                            values.push(Value::Thunk(thunk));
+                        }
+                        (None, None) => {
+                            return Err(NixError::Type(format!(
+                                "{} called without required argument '{}', at {}",
+                                fun.show_name_pos(),
+                                formal.name,
+                                pos
+                            )))
+                        }
+                    }
+                }
+
+                if !formals.ellipsis && attrs_used != attrs.len() {
+                    let unexpected = attrs
+                        .0
+                        .keys()
+                        .filter(|&&name| !formals.has(name))
+                        .min_by_key(|name| name.id())
+                        .expect("an argument isn't a formal");
+                    return Err(NixError::Type(format!(
+                        "{} called with unexpected argument '{}', at {}",
+                        fun.show_name_pos(),
+                        unexpected,
+                        pos
+                    )));
+                }
+            }
+        }
+
+        let env = self
+            .arena
+            .alloc_env(Env::new(Some(closure), Level(0), EnvInner::Plain(values)));
+        for (thunk, def) in defaults {
+            thunk.replace_state(ThunkState::Thunk(Thunk { env, expr: def }));
+        }
+        fun.body.eval(self, env)
+    }
+
+    /// Convert a value to a string, adding the context of everything in it
+    /// to `context`.
+    ///
+    /// Strings, paths, and sets with `__toString` or `outPath` can always
+    /// be coerced. `coerce_more`, as used by `toString`, also allows
+    /// numbers, Booleans, null and lists. Paths are added to the store
+    /// unless `copy_to_store` is false, in which case they're kept as-is.
+    pub fn coerce_to_string(
+        &self,
+        value: Value<'arena>,
+        context: &mut NixContext,
+        coerce_more: bool,
+        copy_to_store: bool,
+        pos: &Pos,
+    ) -> NixResult<String> {
+        let value = self.force_value(value, pos)?;
+        match value {
+            Value::String(s) => {
+                context.extend(s.context);
+                return Ok(s.s);
+            }
+            Value::Path(path) => {
+                let path = if copy_to_store {
+                    self.copy_path_to_store(context, &path, pos)?
+                } else {
+                    path
+                };
+                return Ok(path.to_string_lossy().into_owned());
+            }
+            Value::Attrs(ref attrs) => {
+                if let Some(to_string) = attrs.get(self.sToString) {
+                    let s = self.call_function(to_string.value.clone(), value.clone(), pos)?;
+                    return self.coerce_to_string(s, context, coerce_more, copy_to_store, pos);
+                }
+                if let Some(out_path) = attrs.get(self.sOutPath) {
+                    let out_path = out_path.value.clone();
+                    return self.coerce_to_string(
+                        out_path,
+                        context,
+                        coerce_more,
+                        copy_to_store,
+                        pos,
+                    );
+                }
+            }
+            _ if coerce_more => match value {
+                // Note that `false` and `null` coerce to an empty string, so
+                // that `"${toString false}"` is empty.
+                Value::Bool(true) => return Ok("1".to_owned()),
+                Value::Bool(false) | Value::Null => return Ok(String::new()),
+                Value::Int(n) => return Ok(n.to_string()),
+                Value::Float(n) => return Ok(format!("{:.6}", n.into_inner())),
+                Value::List(elems) => {
+                    let mut s = String::new();
+                    let len = elems.len();
+                    for (i, elem) in elems.iter().enumerate() {
+                        let elem = self.force_value(elem.clone(), pos)?;
+                        let is_empty_list = matches!(&elem, Value::List(l) if l.is_empty());
+                        s.push_str(&self.coerce_to_string(
+                            elem,
+                            context,
+                            coerce_more,
+                            copy_to_store,
+                            pos,
+                        )?);
+                        // Upstream doesn't separate empty lists from what
+                        // follows them.
+                        if i + 1 < len && !is_empty_list {
+                            s.push(' ');
+                        }
+                    }
+                    return Ok(s);
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+        Err(NixError::Type(format!(
+            "cannot coerce {} to a string, at {}",
+            value.show_type(),
+            pos
+        )))
+    }
+
+    /// The path `path` is added to the store at, which is also added to
+    /// `context`.
+    pub fn copy_path_to_store(
+        &self,
+        context: &mut NixContext,
+        path: &Path,
+        pos: &Pos,
+    ) -> NixResult<PathBuf> {
+        let name = path
+            .file_name()
+            .map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+        if name.ends_with(store::DRV_EXTENSION) {
+            return Err(NixError::Eval(format!(
+                "file names are not allowed to end in '{}', at {}",
+                store::DRV_EXTENSION,
+                pos
+            )));
+        }
+        let cached = self.src_to_store.borrow().get(path).cloned();
+        let dst_path = match cached {
+            Some(dst_path) => dst_path,
+            None => {
+                let dst_path = store::compute_store_path_for_path(&name, path).map_err(|err| {
+                    NixError::Eval(format!(
+                        "cannot copy '{}' to the store: {}, at {}",
+                        path.display(),
+                        err,
+                        pos
+                    ))
+                })?;
+                self.src_to_store
+                    .borrow_mut()
+                    .insert(path.to_owned(), dst_path.clone());
+                dst_path
+            }
+        };
+        context.insert(ContextElem::Opaque(dst_path.to_string_lossy().into_owned()));
+        Ok(dst_path)
+    }
+
+    /// Whether the set has `type = "derivation"`.
+    pub fn is_derivation(&self, attrs: &Bindings<'arena>) -> NixResult<bool> {
+        Ok(match attrs.get(self.sType) {
+            Some(attr) => match self.force_value(attr.value.clone(), &attr.pos)? {
+                Value::String(s) => s.s == "derivation",
+                _ => false,
+            },
+            None => false,
+        })
+    }
+
+    /// Compare two values like `==`. Integers and floats compare
+    /// numerically, lists and sets element by element, derivations by
+    /// their `outPath`, and string context is ignored. Functions are never
+    /// equal, unless they're the same shared value inside a list or set.
+    pub fn eq_values(&self, v1: Value<'arena>, v2: Value<'arena>) -> NixResult<bool> {
+        let same = match (&v1, &v2) {
+            (Value::Thunk(a), Value::Thunk(b)) => a == b,
+            _ => false,
+        };
+        let v1 = self.force_value(v1, &Pos::Undefined)?;
+        let v2 = self.force_value(v2, &Pos::Undefined)?;
+        if same {
+            return Ok(true);
+        }
+
+        Ok(match (v1, v2) {
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Int(a), Value::Float(b)) | (Value::Float(b), Value::Int(a)) => {
+                a as f64 == b.into_inner()
+            }
+            (Value::Float(a), Value::Float(b)) => a == b,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a.s == b.s,
+            (Value::Path(a), Value::Path(b)) => a == b,
+            (Value::Null, Value::Null) => true,
+            (Value::List(a), Value::List(b)) => {
+                if a.len() != b.len() {
+                    return Ok(false);
+                }
+                for (a, b) in a.iter().zip(b.iter()) {
+                    if !self.eq_values(a.clone(), b.clone())? {
+                        return Ok(false);
+                    }
+                }
+                true
+            }
+            (Value::Attrs(a), Value::Attrs(b)) => {
+                if self.is_derivation(&a)? && self.is_derivation(&b)? {
+                    if let (Some(a), Some(b)) = (a.get(self.sOutPath), b.get(self.sOutPath)) {
+                        return self.eq_values(a.value.clone(), b.value.clone());
+                    }
+                }
+                if a.len() != b.len() {
+                    return Ok(false);
+                }
+                for (name, a) in a.0.iter() {
+                    match b.get(*name) {
+                        Some(b) if self.eq_values(a.value.clone(), b.value.clone())? => {}
+                        _ => return Ok(false),
+                    }
+                }
+                true
+            }
+            // Functions are incomparable.
+            (Value::Lambda(_), _) | (Value::PrimOp(_), _) | (Value::PrimOpApp(_), _) => false,
+            (v1, v2) if std::mem::discriminant(&v1) != std::mem::discriminant(&v2) => false,
+            (v1, v2) => {
+                return Err(NixError::Eval(format!(
+                    "cannot compare {} with {}",
+                    v1.show_type(),
+                    v2.show_type()
+                )))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn parse_and_eval<'arena>(state: &EvalState<'arena>, source: &str) -> NixResult<Value<'arena>> {
+        state
+            .parse_expr_from_string(source, Path::new("/base/dir"))
+            .and_then(|expr| state.eval(expr))
+    }
+
+    /// Deeply force `result` and print the value, or the error.
+    fn show<'arena>(state: &EvalState<'arena>, result: NixResult<Value<'arena>>) -> String {
+        match result.and_then(|value| state.force_value_deep(value)) {
+            Ok(value) => value.to_string(),
+            Err(err) => format!("error: {}", err),
+        }
+    }
+
+    /// Deeply evaluate `source` and print the value, or the error.
+    pub(crate) fn eval_in(state: &EvalState<'_>, source: &str) -> String {
+        show(state, parse_and_eval(state, source))
+    }
+
+    pub(crate) fn eval(source: &str) -> String {
+        let arena = Arena::new();
+        eval_in(&EvalState::new(&arena), source)
+    }
+
+    /// Call the function `source` evaluates to with `arg`, and print the
+    /// result like `eval`.
+    pub(crate) fn apply(source: &str, arg: NixString) -> String {
+        let arena = Arena::new();
+        let state = EvalState::new(&arena);
+        let result = parse_and_eval(&state, source)
+            .and_then(|fun| state.call_function(fun, Value::String(arg), &Pos::Undefined));
+        show(&state, result)
+    }
+
+    /// Evaluate `source` to a list without forcing its elements.
+    pub(crate) fn eval_list<'arena>(state: &EvalState<'arena>, source: &str) -> Vec<Value<'arena>> {
+        let list = parse_and_eval(state, source)
+            .and_then(|value| state.force_list(value, &Pos::Undefined))
+            .unwrap();
+        list.to_vec()
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(eval("1"), "1");
+        assert_eq!(eval("2.5"), "2.5");
+        assert_eq!(eval(r#""a${"b"}c""#), r#""abc""#);
+        assert_eq!(eval("./x"), "/base/dir/x");
+        assert_eq!(eval("[ 1 (2) \"x\" ]"), r#"[ 1 2 "x" ]"#);
+        assert_eq!(eval("{ a = 1; b = [ 2 ]; }"), "{ a = 1; b = [ 2 ]; }");
+        assert_eq!(eval("null"), "null");
+    }
+
+    #[test]
+    fn expressions() {
+        assert_eq!(eval("let a = 1; in a"), "1");
+        assert_eq!(eval("{ a.b = 1; }.a.b"), "1");
+        assert_eq!(eval("{ a = 1; } ? a"), "true");
+        assert_eq!(eval("(x: x + 1) 2"), "3");
+        assert_eq!(eval("with { a = 1; }; a"), "1");
+        assert_eq!(eval("if true then 1 else 2"), "1");
+        assert_eq!(eval("assert true; 1"), "1");
+        assert_eq!(eval("!false"), "true");
+        assert_eq!(eval("1 == 1"), "true");
+        assert_eq!(
+            eval("__curPos"),
+            r#"{ column = 1; file = "(string)"; line = 1; }"#
+        );
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(
+            eval("{ a = x: x; b = builtins.sub; c = builtins.sub 1; }"),
+            "{ a = <LAMBDA>; b = <PRIMOP>; c = <PRIMOP-APP>; }"
+        );
+    }
+
+    #[test]
+    fn laziness() {
+        assert_eq!(eval("let x = 1 + true; in 1"), "1");
+        assert_eq!(eval("[ 1 ] == [ 1 ] || 1 + true"), "true");
+        assert_eq!(eval("(x: 1) (1 + true)"), "1");
+    }
+
+    #[test]
+    fn type_errors() {
+        assert_eq!(
+            eval("if 1 then 2 else 3"),
+            "error: type error: value is an integer while a Boolean was expected, at (string):1:4"
+        );
+        assert_eq!(
+            eval("! (1)"),
+            "error: type error: value is an integer while a Boolean was expected, at (string):1:3"
+        );
+        assert_eq!(
+            eval("{}.a.b"),
+            "error: attribute 'a.b' missing, at (string):1:1"
+        );
+    }
+
+    #[test]
+    fn cyclic_values() {
+        assert_eq!(eval("let x = { inherit x; }; in x"), "{ x = «repeated»; }");
+        assert_eq!(eval("let x = [ x ]; in x"), "[ «repeated» ]");
+        assert_eq!(
+            eval("let a = { x = 1; }; in [ a a { } { } ]"),
+            "[ { x = 1; } «repeated» { } { } ]"
+        );
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let arena = Arena::new();
+        let mut state = EvalState::new(&arena);
+        state.max_call_depth = 20;
+        assert_eq!(
+            eval_in(
+                &state,
+                "let f = n: if n == 0 then 0 else 1 + f (n - 1); in f 10"
+            ),
+            "10"
+        );
+        assert_eq!(
+            eval_in(&state, "let f = n: 1 + f n; in f 1"),
+            "error: stack overflow; max-call-depth exceeded, at (string):1:16"
+        );
+        assert_eq!(
+            eval_in(&state, "let f = n: [ (f n) ]; in f 1"),
+            "error: stack overflow; max-call-depth exceeded, at (string):1:15"
+        );
+    }
+
+    #[test]
+    fn thunk_sharing() {
+        let arena = Arena::new();
+        let state = EvalState::new(&arena);
+        let elems = eval_list(&state, "let x = 1 + 1; in [ x x ]");
+        let (a, b) = match (&elems[0], &elems[1]) {
+            (Value::Thunk(a), Value::Thunk(b)) => (*a, *b),
+            elems => panic!("expected thunks, got {:?}", elems),
+        };
+        assert_eq!(a, b);
+        assert_eq!(
+            state
+                .force_value(elems[0].clone(), &Pos::Undefined)
+                .unwrap(),
+            Value::Int(2)
+        );
+        assert_eq!(*b.state(), ThunkState::Value(Value::Int(2)));
+    }
+
+    #[test]
+    fn failed_thunk_is_retried() {
+        let arena = Arena::new();
+        let state = EvalState::new(&arena);
+        let elems = eval_list(&state, "let x = 1 + true; in [ x x ]");
+        let error = |value| match state.force_value(value, &Pos::Undefined) {
+            Ok(value) => panic!("expected an error, got {}", value),
+            Err(err) => err.to_string(),
+        };
+        let first = error(elems[0].clone());
+        assert_eq!(first, "cannot add a Boolean to an integer, at (string):1:9");
+        assert_eq!(error(elems[1].clone()), first);
+        assert_eq!(
+            eval("let f = x: { a = x; b = x; }; y = f (1 + 1); in y.a + y.b"),
+            "4"
+        );
+    }
+
+    #[test]
+    fn infinite_recursion() {
+        assert_eq!(
+            eval("let x = x; in x"),
+            "error: infinite recursion encountered, at (string):1:9"
+        );
+        assert_eq!(
+            eval("let x = x + 1; in x"),
+            "error: infinite recursion encountered, at (string):1:9"
+        );
+        assert_eq!(
+            eval("rec { a = b; b = a; }.a"),
+            "error: infinite recursion encountered, at (string):1:18"
+        );
+        assert_eq!(eval("let f = x: f x; in 1"), "1");
+    }
+
+    #[test]
+    fn with_scopes() {
+        assert_eq!(eval("let a = 1; in with { a = 2; }; a"), "1");
+        assert_eq!(eval("with { a = 1; }; with { a = 2; }; a"), "2");
+        assert_eq!(eval("with { a = 1; }; with { b = 2; }; a"), "1");
+        assert_eq!(eval("(a: with { a = 3; }; a) 1"), "1");
+        assert_eq!(
+            eval("with { }; a"),
+            "error: undefined variable 'a' at '(string):1:11'"
+        );
+        assert_eq!(eval("with 1; 2"), "2");
+        assert_eq!(
+            eval("with 1; a"),
+            "error: type error: value is an integer while a set was expected, at (string):1:9"
+        );
+    }
+
+    #[test]
+    fn formals() {
+        assert_eq!(eval("(x: y: x) 1 2"), "1");
+        assert_eq!(eval("({ a, b ? a + 1 }: b) { a = 1; }"), "2");
+        assert_eq!(eval("({ a ? 1 }: a) { a = 2; }"), "2");
+        assert_eq!(eval("({ a ? b, b ? 2 }: a) { }"), "2");
+        assert_eq!(eval("({ a, ... }: a) { a = 1; b = 2; }"), "1");
+        assert_eq!(eval("(args@{ a, ... }: args.b) { a = 1; b = 2; }"), "2");
+        assert_eq!(eval("({ a }@args: args.a) { a = 1; }"), "1");
+        assert_eq!(eval("({ a ? 1 }@args: args) { }"), "{ }");
+    }
+
+    #[test]
+    fn formals_errors() {
+        assert_eq!(
+            eval("({ a }: a) { a = 1; b = 2; }"),
+            "error: type error: anonymous function at (string):1:2 called with unexpected argument 'b', at (string):1:1"
+        );
+        assert_eq!(
+            eval("({ a, b }: a) { }"),
+            "error: type error: anonymous function at (string):1:2 called without required argument 'a', at (string):1:1"
+        );
+        assert_eq!(
+            eval("let f = { a }: a; in f { }"),
+            "error: type error: 'f' at (string):1:9 called without required argument 'a', at (string):1:22"
+        );
+        assert_eq!(
+            eval("{ f = { a }: a; }.f { a = 1; b = 2; }"),
+            "error: type error: 'f' at (string):1:7 called with unexpected argument 'b', at (string):1:1"
+        );
+        assert_eq!(
+            eval("({ a }: a) 1"),
+            "error: type error: value is an integer while a set was expected, at (string):1:1"
+        );
+        assert_eq!(
+            eval("(x: x) 1 2"),
+            "error: type error: attempt to call something which is not a function but an integer, at (string):1:1"
+        );
+    }
+
+    #[test]
+    fn functors() {
+        assert_eq!(eval("{ __functor = self: x: self.n + x; n = 1; } 2"), "3");
+        assert_eq!(
+            eval("let f = { __functor = self: x: if x == 0 then self.n else self (x - 1); n = 5; }; in f 3"),
+            "5"
+        );
+        assert_eq!(
+            eval("{ __functor = 1; } 2"),
+            "error: type error: attempt to call something which is not a function but an integer, at (string):1:1"
+        );
+
+        let arena = Arena::new();
+        let mut state = EvalState::new(&arena);
+        state.max_call_depth = 20;
+        assert_eq!(
+            eval_in(&state, "{ __functor = self: self; } 1"),
+            "error: stack overflow; max-call-depth exceeded, at (string):1:1"
+        );
+    }
+
+    #[test]
+    fn equality() {
+        assert_eq!(eval("1 == 1.0"), "true");
+        assert_eq!(eval(r#"1 == "1""#), "false");
+        assert_eq!(eval("1.5 != 1.5"), "false");
+        assert_eq!(eval("null == null"), "true");
+        assert_eq!(eval("./a == ./a"), "true");
+        assert_eq!(eval(r#"./a == "/base/dir/a""#), "false");
+        assert_eq!(eval("[ 1 [ 2 ] ] == [ 1 [ 2 ] ]"), "true");
+        assert_eq!(eval("[ 1 ] == [ 1 2 ]"), "false");
+        assert_eq!(
+            eval("{ a = 1; b = { c = 2; }; } == { b = { c = 2; }; a = 1; }"),
+            "true"
+        );
+        assert_eq!(eval("{ a = 1; } == { a = 2; }"), "false");
+        assert_eq!(eval("{ a = 1; } != { a = 1; b = 2; }"), "true");
+        assert_eq!(eval("{ a = 1 + true; } == { b = 1; }"), "false");
+        assert_eq!(
+            eval("[ (1 + true) ] == [ 2 ]"),
+            "error: cannot add a Boolean to an integer, at (string):1:4"
+        );
+    }
+
+    #[test]
+    fn derivation_equality() {
+        assert_eq!(
+            eval(
+                r#"{ outPath = "x"; type = "derivation"; a = 1; } == { outPath = "x"; type = "derivation"; a = 2; }"#
+            ),
+            "true"
+        );
+        assert_eq!(
+            eval(r#"{ type = "derivation"; a = 1; } == { type = "derivation"; a = 2; }"#),
+            "false"
+        );
+    }
+
+    #[test]
+    fn function_equality() {
+        assert_eq!(eval("(x: x) == (x: x)"), "false");
+        assert_eq!(eval("let f = x: x; in f == f"), "false");
+        assert_eq!(eval("builtins.sub == builtins.sub"), "false");
+        // Shared values inside lists and sets are equal without being
+        // compared.
+        assert_eq!(eval("let f = x: x; in [ f ] == [ f ]"), "true");
+        assert_eq!(eval("let f = x: x; in { a = f; } == { a = f; }"), "true");
+    }
+
+    #[test]
+    fn eq_values_ignores_context() {
+        let arena = Arena::new();
+        let state = EvalState::new(&arena);
+        let mut with_context = NixString::new("/nix/store/x".to_owned());
+        with_context
+            .context
+            .insert(ContextElem::Opaque("/nix/store/x".to_owned()));
+        let without_context = NixString::new("/nix/store/x".to_owned());
+        assert!(state
+            .eq_values(Value::String(with_context), Value::String(without_context))
+            .unwrap());
+    }
+
+    #[test]
+    fn to_string() {
+        assert_eq!(eval("toString 1"), r#""1""#);
+        assert_eq!(eval("toString 2.5"), r#""2.500000""#);
+        assert_eq!(eval("toString true"), r#""1""#);
+        assert_eq!(eval("toString false"), r#""""#);
+        assert_eq!(eval("toString null"), r#""""#);
+        assert_eq!(eval(r#"toString [ 1 "a" null [ 2 ] ]"#), r#""1 a  2""#);
+        assert_eq!(eval("toString ./a"), r#""/base/dir/a""#);
+        assert_eq!(
+            eval(r#"toString { __toString = self: "s${self.x}"; x = "y"; }"#),
+            r#""sy""#
+        );
+        assert_eq!(eval("toString { __toString = self: 1; }"), r#""1""#);
+        assert_eq!(eval(r#"toString { outPath = "/p"; }"#), r#""/p""#);
+        assert_eq!(
+            eval("toString { }"),
+            "error: type error: cannot coerce a set to a string, at (string):1:1"
+        );
+        assert_eq!(
+            eval("toString (x: x)"),
+            "error: type error: cannot coerce a function to a string, at (string):1:1"
+        );
+    }
+
+    #[test]
+    fn interpolation() {
+        assert_eq!(eval(r#""${{ outPath = "/p"; }}""#), r#""/p""#);
+        assert_eq!(eval(r#""${{ __toString = self: "t"; }}""#), r#""t""#);
+        assert_eq!(
+            eval(r#""${1}""#),
+            "error: type error: cannot coerce an integer to a string, at (string):1:1"
+        );
+        assert_eq!(
+            eval(r#""${true}""#),
+            "error: type error: cannot coerce a Boolean to a string, at (string):1:1"
+        );
+        assert_eq!(
+            eval(r#""${null}""#),
+            "error: type error: cannot coerce null to a string, at (string):1:1"
+        );
+        assert_eq!(
+            eval(r#""${[ 1 ]}""#),
+            "error: type error: cannot coerce a list to a string, at (string):1:1"
+        );
+    }
 
-        // Ok(Value::Blackhole)
+    #[test]
+    fn path_concatenation() {
+        assert_eq!(eval(r#"./a + "b""#), "/base/dir/ab");
+        assert_eq!(eval(r#"./a + "/b""#), "/base/dir/a/b");
+        assert_eq!(eval("./a + ./b"), "/base/dir/a/base/dir/b");
+        assert_eq!(eval(r#"{ outPath = "/p"; } + ./x"#), r#""/p/base/dir/x""#);
     }
 }
@@ -1,6 +1,8 @@
+pub mod arena;
 pub mod attr_path;
 pub mod attr_set;
 pub mod common_eval_args;
+pub mod cst;
 pub mod env;
 pub mod err;
 pub mod eval;
@@ -11,8 +13,10 @@ pub mod imported_drv_to_derivation;
 pub mod json_to_value;
 pub mod names;
 pub mod nix_expr;
+pub mod parser;
 pub mod pos;
 pub mod primops;
+pub mod store;
 pub mod symbol_table;
 pub mod value;
 pub mod value_to_json;
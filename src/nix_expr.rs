@@ -1,61 +1,182 @@
-use std::borrow::{Borrow, ToOwned};
+use std::cell::Cell;
 use std::collections::HashMap;
+use std::fmt;
+use std::fmt::{Display, Formatter, Write};
 use std::path::PathBuf;
+use std::rc::Rc;
 
-use crate::env::{Displ, Env, Level, StaticEnv, StaticEnvLevel};
+use crate::attr_set::Bindings;
+use crate::env::{Displ, Env, EnvInner, Level, StaticEnv};
 use crate::err::{NixError, NixResult};
-use crate::eval::EvalState;
+use crate::eval::{EvalState, ShouldEval};
+use crate::parser::canon_path;
 use crate::pos::Pos;
 use crate::symbol_table::{Symbol, SymbolTable};
-use crate::value::{NixFloat, NixInt, Value};
+use crate::value::{
+    Lambda, NixContext, NixFloat, NixInt, NixString, SharedThunk, Thunk, ThunkState, Value,
+};
 
-#[derive(Debug, PartialEq)]
-pub struct AttrName<'arena> {
-    pub symbol: Symbol<'arena>,
-    pub expr: Box<Expr<'arena>>,
+/// A component of an attribute path, like `a`, `"b"` or `${c}`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttrName {
+    Static(Symbol),
+    Dynamic(Box<Expr>),
 }
 
-pub type AttrPath<'arena> = Vec<AttrName<'arena>>;
+pub type AttrPath = Vec<AttrName>;
 
-#[derive(Debug, PartialEq)]
-pub struct AttrDef<'arena> {
-    inherited: bool,
-    expr: Box<Expr<'arena>>,
-    pos: Pos<'arena>,
+/// Render an attribute path like upstream, e.g. `a."b c"."${d}"`.
+pub fn show_attr_path(path: &[AttrName]) -> String {
+    show_evaluated_attr_path(&[], path)
+}
+
+/// Like `show_attr_path`, but the first components of `path` are shown as
+/// the names they were evaluated to, given in `names`.
+pub fn show_evaluated_attr_path(names: &[Symbol], path: &[AttrName]) -> String {
+    let names = names.iter().map(|name| ShowId(name.as_str()).to_string());
+    let rest = path[names.len()..].iter().map(|name| match name {
+        AttrName::Static(name) => ShowId(name.as_str()).to_string(),
+        AttrName::Dynamic(expr) => format!("\"${{{}}}\"", expr),
+    });
+    names.chain(rest).collect::<Vec<_>>().join(".")
+}
+
+/// Displays a string as a Nix string literal.
+struct ShowString<'a>(&'a str);
+
+impl Display for ShowString<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_char('"')?;
+        for c in self.0.chars() {
+            match c {
+                '"' | '\\' | '$' => write!(f, "\\{}", c)?,
+                '\n' => f.write_str("\\n")?,
+                '\r' => f.write_str("\\r")?,
+                '\t' => f.write_str("\\t")?,
+                c => f.write_char(c)?,
+            }
+        }
+        f.write_char('"')
+    }
+}
+
+/// Displays an identifier, quoting it if it isn't a valid one.
+struct ShowId<'a>(&'a str);
+
+impl Display for ShowId<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let s = self.0;
+        let valid = s.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '\'' || c == '-');
+        // Upstream only quotes this one keyword.
+        if valid && s != "if" {
+            f.write_str(s)
+        } else {
+            ShowString(s).fmt(f)
+        }
+    }
+}
+
+/// Format a float like C++'s default stream output, which is `printf`'s
+/// `%g`.
+pub fn show_float(n: f64) -> String {
+    if n.is_nan() {
+        return "nan".to_owned();
+    }
+    if n.is_infinite() {
+        return if n < 0.0 { "-inf" } else { "inf" }.to_owned();
+    }
+    if n == 0.0 {
+        return if n.is_sign_negative() { "-0" } else { "0" }.to_owned();
+    }
+
+    const PRECISION: i32 = 6;
+    let strip_zeros = |s: &str| -> String {
+        if s.contains('.') {
+            s.trim_end_matches('0').trim_end_matches('.').to_owned()
+        } else {
+            s.to_owned()
+        }
+    };
+    // The exponent after rounding to the precision.
+    let sci = format!("{:.*e}", (PRECISION - 1) as usize, n);
+    let (mantissa, exponent) = sci.split_at(sci.find('e').unwrap());
+    let exponent: i32 = exponent[1..].parse().unwrap();
+    if !(-4..PRECISION).contains(&exponent) {
+        format!(
+            "{}e{}{:02}",
+            strip_zeros(mantissa),
+            if exponent < 0 { '-' } else { '+' },
+            exponent.abs()
+        )
+    } else {
+        strip_zeros(&format!("{:.*}", (PRECISION - 1 - exponent) as usize, n))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttrDef {
+    pub inherited: bool,
+    pub expr: Box<Expr>,
+    pub pos: Pos,
     /// Displacement
-    displ: Displ,
+    pub displ: Displ,
 }
 
-#[derive(Debug, PartialEq)]
-pub struct DynamicAttrDef<'arena> {
-    name_expr: Box<Expr<'arena>>,
-    value_expr: Box<Expr<'arena>>,
-    pos: Pos<'arena>,
+impl AttrDef {
+    pub fn new(expr: Expr, pos: Pos) -> Self {
+        Self {
+            inherited: false,
+            expr: Box::new(expr),
+            pos,
+            displ: Displ(0),
+        }
+    }
 }
 
-#[derive(Debug, PartialEq)]
-pub struct Formal<'arena> {
-    name: Symbol<'arena>,
-    def: Box<Expr<'arena>>,
+#[derive(Clone, Debug, PartialEq)]
+pub struct DynamicAttrDef {
+    pub name_expr: Box<Expr>,
+    pub value_expr: Box<Expr>,
+    pub pos: Pos,
 }
 
-#[derive(Debug, PartialEq)]
-pub struct Formals<'arena> {
-    formals: Vec<Formal<'arena>>,
-    ellipsis: bool,
+#[derive(Clone, Debug, PartialEq)]
+pub struct Formal {
+    pub name: Symbol,
+    /// The default value, if any.
+    pub def: Option<Box<Expr>>,
 }
 
-pub trait ExprExt {
-    fn bind_vars<'env>(&mut self, env: &StaticEnv<'env>) -> NixResult<()> {
+#[derive(Clone, Debug, PartialEq)]
+pub struct Formals {
+    pub formals: Vec<Formal>,
+    pub ellipsis: bool,
+}
+
+impl Formals {
+    pub fn has(&self, name: Symbol) -> bool {
+        self.formals.iter().any(|formal| formal.name == name)
+    }
+}
+
+pub trait ExprExt<'arena> {
+    /// Resolve the variables in this expression to their level and
+    /// displacement in `env`.
+    fn bind_vars(&mut self, _env: &StaticEnv<'_>) -> NixResult<()> {
         Ok(())
     }
-    fn eval<'a>(&'a self, state: &EvalState, env: &Env) -> NixResult<&'a Value>;
-    fn maybe_thunk<'a>(&'a self, state: &EvalState, env: &Env) -> &'a Value;
-    /// Storing function names.
-    fn set_name<'env>(name: Symbol<'env>) {}
+
+    /// Evaluate this expression to weak head normal form.
+    fn eval(
+        &'arena self,
+        state: &EvalState<'arena>,
+        env: &'arena Env<'arena>,
+    ) -> NixResult<Value<'arena>>;
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum OpKind {
     App,
     Eq,
@@ -67,29 +188,426 @@ pub enum OpKind {
     ConcatLists,
 }
 
-#[derive(Debug, PartialEq)]
-pub struct BinOp<'arena> {
-    kind: OpKind,
-    pos: Pos<'arena>,
-    e1: Box<Expr<'arena>>,
-    e2: Box<Expr<'arena>>,
+#[derive(Clone, Debug, PartialEq)]
+pub struct BinOp {
+    pub kind: OpKind,
+    pub pos: Pos,
+    pub e1: Box<Expr>,
+    pub e2: Box<Expr>,
+}
+
+impl BinOp {
+    pub fn new(kind: OpKind, pos: Pos, e1: Expr, e2: Expr) -> Self {
+        Self {
+            kind,
+            pos,
+            e1: Box::new(e1),
+            e2: Box::new(e2),
+        }
+    }
 }
 
-#[derive(Debug, PartialEq)]
-pub struct ExprVar<'arena> {
-    pub pos: Pos<'arena>,
-    pub name: Symbol<'arena>,
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExprVar {
+    pub pos: Pos,
+    pub name: Symbol,
     pub from_with: bool,
     pub level: Level,
     /// Displacement
     pub displ: Displ,
 }
 
-impl<'arena> ExprExt for ExprVar<'arena> {
-    fn bind_vars<'env>(&mut self, env: &StaticEnv<'env>) -> NixResult<()> {
+impl ExprVar {
+    /// Create an unbound variable reference; its level and displacement are
+    /// filled in by `bind_vars`.
+    pub fn new(pos: Pos, name: Symbol) -> Self {
+        Self {
+            pos,
+            name,
+            from_with: false,
+            level: Level(0),
+            displ: Displ(0),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExprSelect {
+    pub pos: Pos,
+    pub expr: Box<Expr>,
+    /// The `or` default, if any.
+    pub def: Option<Box<Expr>>,
+    pub attr_path: AttrPath,
+}
+
+/// `!expr`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExprOpNot {
+    /// The position of `expr`, for the error when it isn't a Boolean.
+    pub pos: Pos,
+    pub expr: Box<Expr>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExprOpHasAttr {
+    pub expr: Box<Expr>,
+    pub attr_path: AttrPath,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExprAttrs {
+    pub recursive: bool,
+    pub attrs: HashMap<Symbol, AttrDef>,
+    pub dynamic_attrs: Vec<DynamicAttrDef>,
+}
+
+impl ExprAttrs {
+    /// Add `path = expr`, creating or merging into nested attribute sets for
+    /// paths like `a.b.c`, so `a.b.c = 1; a.b.d = 2;` defines `a.b` once.
+    ///
+    /// Fails if the path is already defined, in which case nothing is added.
+    pub fn add_attr(&mut self, path: AttrPath, expr: Expr, pos: Pos) -> NixResult<()> {
+        let dup = |prev: Pos| {
+            Err(NixError::DuplicateAttr(
+                show_attr_path(&path),
+                pos.to_owned(),
+                prev.to_owned(),
+            ))
+        };
+
+        let mut attrs = self;
+        for name in &path[..path.len() - 1] {
+            attrs = match name {
+                AttrName::Static(name) => {
+                    let def = attrs.attrs.entry(*name).or_insert_with(|| {
+                        AttrDef::new(Expr::Attrs(ExprAttrs::default()), pos.clone())
+                    });
+                    let (inherited, prev) = (def.inherited, def.pos.clone());
+                    match &mut *def.expr {
+                        Expr::Attrs(nested) if !inherited => nested,
+                        _ => return dup(prev),
+                    }
+                }
+                AttrName::Dynamic(name_expr) => {
+                    attrs.dynamic_attrs.push(DynamicAttrDef {
+                        name_expr: name_expr.clone(),
+                        value_expr: Box::new(Expr::Attrs(ExprAttrs::default())),
+                        pos: pos.clone(),
+                    });
+                    match &mut *attrs.dynamic_attrs.last_mut().unwrap().value_expr {
+                        Expr::Attrs(nested) => nested,
+                        _ => unreachable!(),
+                    }
+                }
+            };
+        }
+
+        match path.last().expect("attribute paths are never empty") {
+            AttrName::Static(name) => {
+                if let Some(prev) = attrs.attrs.get(name) {
+                    return dup(prev.pos.clone());
+                }
+                expr.set_name(*name);
+                attrs.attrs.insert(*name, AttrDef::new(expr, pos));
+            }
+            AttrName::Dynamic(name_expr) => attrs.dynamic_attrs.push(DynamicAttrDef {
+                name_expr: name_expr.clone(),
+                value_expr: Box::new(expr),
+                pos,
+            }),
+        }
+        Ok(())
+    }
+
+    /// Add `inherit name;`, or `inherit (from) name;` if `from` is given.
+    ///
+    /// Fails if `name` is already defined, in which case nothing is added.
+    pub fn add_inherited(&mut self, name: Symbol, from: Option<&Expr>, pos: Pos) -> NixResult<()> {
+        if let Some(prev) = self.attrs.get(&name) {
+            return Err(NixError::DuplicateAttr(
+                name.into(),
+                pos.to_owned(),
+                prev.pos.to_owned(),
+            ));
+        }
+        let def = match from {
+            Some(from) => AttrDef::new(
+                Expr::Select(ExprSelect {
+                    pos: pos.clone(),
+                    expr: Box::new(from.clone()),
+                    def: None,
+                    attr_path: vec![AttrName::Static(name)],
+                }),
+                pos,
+            ),
+            None => AttrDef {
+                inherited: true,
+                ..AttrDef::new(Expr::Var(ExprVar::new(pos.clone(), name)), pos)
+            },
+        };
+        self.attrs.insert(name, def);
+        Ok(())
+    }
+
+    /// The static attributes, sorted by name.
+    pub fn sorted_attrs(&self) -> Vec<(Symbol, &AttrDef)> {
+        let mut attrs: Vec<_> = self.attrs.iter().map(|(&name, def)| (name, def)).collect();
+        SymbolTable::global().sort_by_name(&mut attrs, |&(name, _)| name);
+        attrs
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExprLambda {
+    pub pos: Pos,
+    /// The name of the attribute this function is bound to, if any; used in
+    /// error messages. For dynamic attributes, this is set whenever the
+    /// attribute is evaluated.
+    pub name: Cell<Option<Symbol>>,
+    /// The `x` in `x: ...` or `x@{ ... }: ...`.
+    pub arg: Option<Symbol>,
+    /// The `{ a, b ? 1, ... }` pattern, if the function destructures its
+    /// argument.
+    pub formals: Option<Formals>,
+    pub body: Box<Expr>,
+}
+
+impl ExprLambda {
+    /// Describe the function for error messages, like `'f' at foo.nix:1:5`.
+    pub fn show_name_pos(&self) -> String {
+        let name = match self.name.get() {
+            Some(name) => format!("'{}'", name),
+            None => "anonymous function".to_owned(),
+        };
+        match &self.pos {
+            Pos::Known(pos) => format!("{} at {}", name, pos),
+            Pos::Undefined => name,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExprLet {
+    pub attrs: Box<ExprAttrs>,
+    pub body: Box<Expr>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExprWith {
+    pub pos: Pos,
+    pub attrs: Box<Expr>,
+    pub body: Box<Expr>,
+    pub prev_with: Level,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExprIf {
+    /// The position of `cond`, for the error when it isn't a Boolean.
+    pub pos: Pos,
+    pub cond: Box<Expr>,
+    pub then: Box<Expr>,
+    pub else_: Box<Expr>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExprAssert {
+    pub pos: Pos,
+    pub cond: Box<Expr>,
+    /// The source text of `cond`, for the error when the assertion fails.
+    pub cond_source: String,
+    pub body: Box<Expr>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExprConcatStrings {
+    pub pos: Pos,
+    pub force_string: bool,
+    pub exprs: Vec<Expr>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Int(NixInt),
+    Float(NixFloat),
+    String(String),
+    Path(PathBuf),
+    Var(ExprVar),
+    Select(ExprSelect),
+    OpHasAttr(ExprOpHasAttr),
+    Attrs(ExprAttrs),
+    List(Vec<Expr>),
+    Lambda(ExprLambda),
+    Let(ExprLet),
+    With(ExprWith),
+    If(ExprIf),
+    Assert(ExprAssert),
+    OpNot(ExprOpNot),
+    BinOp(BinOp),
+    ConcatStrings(ExprConcatStrings),
+    Pos(Pos),
+    /// Stands in for an expression with a syntax error, in the result of
+    /// `parser::parse_recovering`.
+    Error(Pos),
+}
+
+impl Expr {
+    /// Record the name a function is bound to, so error messages can refer
+    /// to it. `f = x: y: ...` names both lambdas.
+    pub fn set_name(&self, name: Symbol) {
+        if let Expr::Lambda(lambda) = self {
+            lambda.name.set(Some(name));
+            lambda.body.set_name(name);
+        }
+    }
+}
+
+/// Prints the expression as Nix source, exactly like `nix-instantiate
+/// --parse`. Attributes are sorted by name.
+impl Display for Expr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Int(n) => write!(f, "{}", n),
+            Expr::Float(n) => f.write_str(&show_float(n.into_inner())),
+            Expr::String(s) => ShowString(s).fmt(f),
+            Expr::Path(path) => write!(f, "{}", path.display()),
+            Expr::Var(var) => ShowId(var.name.as_str()).fmt(f),
+            Expr::Select(select) => {
+                write!(f, "({}).{}", select.expr, show_attr_path(&select.attr_path))?;
+                if let Some(def) = &select.def {
+                    write!(f, " or ({})", def)?;
+                }
+                Ok(())
+            }
+            Expr::OpHasAttr(has_attr) => write!(
+                f,
+                "(({}) ? {})",
+                has_attr.expr,
+                show_attr_path(&has_attr.attr_path)
+            ),
+            Expr::Attrs(attrs) => {
+                if attrs.recursive {
+                    f.write_str("rec ")?;
+                }
+                f.write_str("{ ")?;
+                for (name, def) in attrs.sorted_attrs() {
+                    if def.inherited {
+                        write!(f, "inherit {} ; ", ShowId(name.as_str()))?;
+                    } else {
+                        write!(f, "{} = {}; ", ShowId(name.as_str()), def.expr)?;
+                    }
+                }
+                for def in &attrs.dynamic_attrs {
+                    write!(f, "\"${{{}}}\" = {}; ", def.name_expr, def.value_expr)?;
+                }
+                f.write_str("}")
+            }
+            Expr::List(elems) => {
+                f.write_str("[ ")?;
+                for elem in elems {
+                    write!(f, "({}) ", elem)?;
+                }
+                f.write_str("]")
+            }
+            Expr::Lambda(lambda) => {
+                f.write_str("(")?;
+                if let Some(formals) = &lambda.formals {
+                    f.write_str("{ ")?;
+                    for (n, formal) in formals.formals.iter().enumerate() {
+                        if n > 0 {
+                            f.write_str(", ")?;
+                        }
+                        ShowId(formal.name.as_str()).fmt(f)?;
+                        if let Some(def) = &formal.def {
+                            write!(f, " ? {}", def)?;
+                        }
+                    }
+                    if formals.ellipsis {
+                        if !formals.formals.is_empty() {
+                            f.write_str(", ")?;
+                        }
+                        f.write_str("...")?;
+                    }
+                    f.write_str(" }")?;
+                    if lambda.arg.is_some() {
+                        f.write_str(" @ ")?;
+                    }
+                }
+                if let Some(arg) = lambda.arg {
+                    ShowId(arg.as_str()).fmt(f)?;
+                }
+                write!(f, ": {})", lambda.body)
+            }
+            Expr::Let(let_) => {
+                f.write_str("(let ")?;
+                for (name, def) in let_.attrs.sorted_attrs() {
+                    if def.inherited {
+                        write!(f, "inherit {}; ", ShowId(name.as_str()))?;
+                    } else {
+                        write!(f, "{} = {}; ", ShowId(name.as_str()), def.expr)?;
+                    }
+                }
+                write!(f, "in {})", let_.body)
+            }
+            Expr::With(with) => write!(f, "(with {}; {})", with.attrs, with.body),
+            Expr::If(if_) => write!(f, "(if {} then {} else {})", if_.cond, if_.then, if_.else_),
+            Expr::Assert(assert) => write!(f, "assert {}; {}", assert.cond, assert.body),
+            Expr::OpNot(not) => write!(f, "(! {})", not.expr),
+            Expr::BinOp(op) if op.kind == OpKind::App => write!(f, "({} {})", op.e1, op.e2),
+            Expr::BinOp(op) => {
+                let op_str = match op.kind {
+                    OpKind::App => unreachable!(),
+                    OpKind::Eq => "==",
+                    OpKind::NEq => "!=",
+                    OpKind::And => "&&",
+                    OpKind::Or => "||",
+                    OpKind::Impl => "->",
+                    OpKind::Update => "//",
+                    OpKind::ConcatLists => "++",
+                };
+                write!(f, "({} {} {})", op.e1, op_str, op.e2)
+            }
+            Expr::ConcatStrings(concat) => {
+                f.write_str("(")?;
+                for (n, e) in concat.exprs.iter().enumerate() {
+                    if n > 0 {
+                        f.write_str(" + ")?;
+                    }
+                    e.fmt(f)?;
+                }
+                f.write_str(")")
+            }
+            Expr::Pos(_) => f.write_str("__curPos"),
+            Expr::Error(_) => f.write_str("«error»"),
+        }
+    }
+}
+
+impl<'arena> Expr {
+    /// The value of this expression if it's available without evaluating
+    /// anything, or a thunk which evaluates it otherwise.
+    pub fn maybe_thunk(
+        &'arena self,
+        state: &EvalState<'arena>,
+        env: &'arena Env<'arena>,
+    ) -> Value<'arena> {
+        let thunk = || Value::Thunk(SharedThunk::new(state.arena, env, self));
+        match self {
+            Expr::Int(_) | Expr::Float(_) | Expr::String(_) | Expr::Path(_) => self
+                .eval(state, env)
+                .expect("evaluating a constant can't fail"),
+            Expr::Var(var) => state
+                .lookup_var(env, var, ShouldEval::No)
+                .unwrap_or_else(|_| thunk()),
+            _ => thunk(),
+        }
+    }
+}
+
+impl<'arena> ExprExt<'arena> for ExprVar {
+    fn bind_vars(&mut self, env: &StaticEnv<'_>) -> NixResult<()> {
         // Check whether the variable appears in the environment. If so,
         // set its level and displacement.
-        let with_level = None;
+        let mut with_level = None;
         for env_level in env.into_iter() {
             with_level = env_level.with_level;
             if let Some(displ) = env_level.env.vars.get(&self.name) {
@@ -100,6 +618,8 @@ impl<'arena> ExprExt for ExprVar<'arena> {
             }
         }
 
+        // Otherwise, the variable must be obtained from the nearest
+        // enclosing `with`.
         match with_level {
             None => Err(NixError::UndefinedVar(
                 self.name.into(),
@@ -113,127 +633,815 @@ impl<'arena> ExprExt for ExprVar<'arena> {
         }
     }
 
-    fn eval<'a>(&'a self, state: &EvalState, env: &Env) -> NixResult<&'a Value> {
-        unimplemented!()
+    fn eval(
+        &'arena self,
+        state: &EvalState<'arena>,
+        env: &'arena Env<'arena>,
+    ) -> NixResult<Value<'arena>> {
+        let value = state.lookup_var(env, self, ShouldEval::Yes)?;
+        state.force_value(value, &self.pos)
+    }
+}
+
+fn bind_attr_path(path: &mut AttrPath, env: &StaticEnv<'_>) -> NixResult<()> {
+    for name in path {
+        if let AttrName::Dynamic(expr) = name {
+            expr.bind_vars(env)?;
+        }
+    }
+    Ok(())
+}
+
+impl<'arena> ExprExt<'arena> for ExprSelect {
+    fn bind_vars(&mut self, env: &StaticEnv<'_>) -> NixResult<()> {
+        self.expr.bind_vars(env)?;
+        if let Some(def) = &mut self.def {
+            def.bind_vars(env)?;
+        }
+        bind_attr_path(&mut self.attr_path, env)
     }
 
-    fn maybe_thunk<'a>(&'a self, state: &EvalState, env: &Env) -> &'a Value {
-        unimplemented!()
+    fn eval(
+        &'arena self,
+        state: &EvalState<'arena>,
+        env: &'arena Env<'arena>,
+    ) -> NixResult<Value<'arena>> {
+        let mut value = self.expr.eval(state, env)?;
+        // The value is forced at the position of the attribute it came from,
+        // if there is one.
+        let mut pos = self.pos.clone();
+        let mut names = Vec::with_capacity(self.attr_path.len());
+        for name in &self.attr_path {
+            let name = state.get_name(name, env, &self.pos)?;
+            names.push(name);
+            let attr = match &self.def {
+                Some(def) => match state.force_value(value, &self.pos)? {
+                    Value::Attrs(attrs) => match attrs.get(name) {
+                        Some(attr) => attr.clone(),
+                        None => return def.eval(state, env),
+                    },
+                    _ => return def.eval(state, env),
+                },
+                None => match state.force_attrs(value, &self.pos)?.get(name) {
+                    Some(attr) => attr.clone(),
+                    None => {
+                        return Err(NixError::MissingAttr(
+                            show_evaluated_attr_path(&names, &self.attr_path),
+                            self.pos.to_owned(),
+                        ))
+                    }
+                },
+            };
+            value = attr.value;
+            if let Pos::Known(_) = attr.pos {
+                pos = attr.pos;
+            }
+        }
+        state.force_value(value, &pos)
     }
 }
 
-#[derive(Debug, PartialEq)]
-pub struct ExprSelect<'arena> {
-    pos: Pos<'arena>,
-    expr: Box<Expr<'arena>>,
-    def: Box<Expr<'arena>>,
-    attr_path: AttrPath<'arena>,
+impl<'arena> ExprExt<'arena> for ExprOpHasAttr {
+    fn bind_vars(&mut self, env: &StaticEnv<'_>) -> NixResult<()> {
+        self.expr.bind_vars(env)?;
+        bind_attr_path(&mut self.attr_path, env)
+    }
+
+    fn eval(
+        &'arena self,
+        state: &EvalState<'arena>,
+        env: &'arena Env<'arena>,
+    ) -> NixResult<Value<'arena>> {
+        let mut value = self.expr.eval(state, env)?;
+        for name in &self.attr_path {
+            let forced = state.force_value(value, &Pos::Undefined)?;
+            let name = state.get_name(name, env, &Pos::Undefined)?;
+            match forced {
+                Value::Attrs(attrs) => match attrs.get(name) {
+                    Some(attr) => value = attr.value.clone(),
+                    None => return Ok(Value::Bool(false)),
+                },
+                _ => return Ok(Value::Bool(false)),
+            }
+        }
+        Ok(Value::Bool(true))
+    }
 }
 
-#[derive(Debug, PartialEq)]
-pub struct ExprOpHasAttr<'arena> {
-    expr: Box<Expr<'arena>>,
-    attr_path: AttrPath<'arena>,
+impl<'arena> ExprAttrs {
+    /// Bind the attributes of a `rec` set or `let` in a new scope where
+    /// they can see each other, and return that scope. Inherited attributes
+    /// come from the enclosing scope.
+    fn bind_rec_vars<'e>(&mut self, env: &'e StaticEnv<'e>) -> NixResult<StaticEnv<'e>>
+    where
+        'arena: 'e,
+    {
+        let mut new_env = StaticEnv::new(false, Some(env));
+        for (displ, (&name, def)) in self.attrs.iter_mut().enumerate() {
+            new_env.vars.insert(name, Displ(displ));
+            def.displ = Displ(displ);
+        }
+        for def in self.attrs.values_mut() {
+            def.expr
+                .bind_vars(if def.inherited { env } else { &new_env })?;
+        }
+        Ok(new_env)
+    }
+
+    /// The environment of a `rec` set or `let`, with a value for each
+    /// attribute at its displacement.
+    fn rec_env(
+        &'arena self,
+        state: &EvalState<'arena>,
+        env: &'arena Env<'arena>,
+    ) -> &'arena Env<'arena> {
+        let mut values = vec![Value::Null; self.attrs.len()];
+        let mut pending = Vec::new();
+        for def in self.attrs.values() {
+            values[def.displ.0] = if def.inherited {
+                def.expr.maybe_thunk(state, env)
+            } else {
+                let thunk = SharedThunk::blackhole(state.arena);
+                pending.push((thunk, &def.expr));
+                Value::Thunk(thunk)
+            };
+        }
+        let env = state
+            .arena
+            .alloc_env(Env::new(Some(env), Level(0), EnvInner::Plain(values)));
+        // The attributes are evaluated in the environment containing them,
+        // so they can refer to each other.
+        for (thunk, expr) in pending {
+            thunk.replace_state(ThunkState::Thunk(Thunk { env, expr }));
+        }
+        env
+    }
 }
 
-#[derive(Debug, PartialEq)]
-pub struct ExprAttrs<'arena> {
-    recursive: bool,
-    attrs: HashMap<Symbol<'arena>, AttrDef<'arena>>,
-    dynamic_attrs: Vec<DynamicAttrDef<'arena>>,
+impl<'arena> ExprExt<'arena> for ExprAttrs {
+    fn bind_vars(&mut self, env: &StaticEnv<'_>) -> NixResult<()> {
+        if self.recursive {
+            let new_env = self.bind_rec_vars(env)?;
+            for def in &mut self.dynamic_attrs {
+                def.name_expr.bind_vars(&new_env)?;
+                def.value_expr.bind_vars(&new_env)?;
+            }
+        } else {
+            for def in self.attrs.values_mut() {
+                def.expr.bind_vars(env)?;
+            }
+            for def in &mut self.dynamic_attrs {
+                def.name_expr.bind_vars(env)?;
+                def.value_expr.bind_vars(env)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn eval(
+        &'arena self,
+        state: &EvalState<'arena>,
+        env: &'arena Env<'arena>,
+    ) -> NixResult<Value<'arena>> {
+        let mut attrs = Bindings::new();
+        let dynamic_env = if self.recursive {
+            let rec_env = self.rec_env(state, env);
+            let values = rec_env.values.borrow();
+            let values = match &*values {
+                EnvInner::Plain(values) => values,
+                _ => unreachable!(),
+            };
+            for (&name, def) in &self.attrs {
+                attrs.insert(name, values[def.displ.0].clone(), def.pos.clone());
+            }
+            rec_env
+        } else {
+            for (&name, def) in &self.attrs {
+                attrs.insert(name, def.expr.maybe_thunk(state, env), def.pos.clone());
+            }
+            env
+        };
+
+        // Dynamic attributes are added after the static ones, so they can't
+        // be referred to in a `rec` set.
+        for def in &self.dynamic_attrs {
+            let name = match def.name_expr.eval(state, dynamic_env)? {
+                Value::Null => continue,
+                name => Symbol::new(&state.force_string_no_ctx(name, &def.pos)?),
+            };
+            if let Some(prev) = attrs.get(name) {
+                return Err(NixError::DuplicateDynamicAttr(
+                    name.into(),
+                    def.pos.to_owned(),
+                    prev.pos.to_owned(),
+                ));
+            }
+            // Lambdas are named after the attribute they're bound to, which
+            // isn't known until now. Like upstream, the expression is renamed
+            // in place, so it has the name it was last bound to.
+            def.value_expr.set_name(name);
+            attrs.insert(
+                name,
+                def.value_expr.maybe_thunk(state, dynamic_env),
+                def.pos.clone(),
+            );
+        }
+        Ok(Value::Attrs(attrs))
+    }
 }
 
-impl<'arena> ExprExt for ExprAttrs<'arena> {
-    fn bind_vars<'env>(&mut self, env: &StaticEnv<'env>) -> NixResult<()> {
-        unimplemented!()
+impl<'arena> ExprExt<'arena> for ExprLambda {
+    fn bind_vars(&mut self, env: &StaticEnv<'_>) -> NixResult<()> {
+        let mut new_env = StaticEnv::new(false, Some(env));
+        if let Some(arg) = self.arg {
+            new_env.vars.insert(arg, Displ(new_env.vars.len()));
+        }
+        if let Some(formals) = &mut self.formals {
+            for formal in &formals.formals {
+                new_env.vars.insert(formal.name, Displ(new_env.vars.len()));
+            }
+            for formal in &mut formals.formals {
+                if let Some(def) = &mut formal.def {
+                    def.bind_vars(&new_env)?;
+                }
+            }
+        }
+        self.body.bind_vars(&new_env)
+    }
+
+    fn eval(
+        &'arena self,
+        _state: &EvalState<'arena>,
+        env: &'arena Env<'arena>,
+    ) -> NixResult<Value<'arena>> {
+        Ok(Value::Lambda(Lambda { env, fun: self }))
     }
+}
 
-    fn eval<'a>(&'a self, state: &EvalState, env: &Env) -> NixResult<&'a Value> {
-        unimplemented!()
+impl<'arena> ExprExt<'arena> for ExprLet {
+    fn bind_vars(&mut self, env: &StaticEnv<'_>) -> NixResult<()> {
+        let new_env = self.attrs.bind_rec_vars(env)?;
+        self.body.bind_vars(&new_env)
     }
 
-    fn maybe_thunk<'a>(&'a self, state: &EvalState, env: &Env) -> &'a Value {
-        unimplemented!()
+    fn eval(
+        &'arena self,
+        state: &EvalState<'arena>,
+        env: &'arena Env<'arena>,
+    ) -> NixResult<Value<'arena>> {
+        let env = self.attrs.rec_env(state, env);
+        self.body.eval(state, env)
     }
 }
 
-#[derive(Debug, PartialEq)]
-pub struct ExprLambda<'arena> {
-    pos: Pos<'arena>,
-    name: Symbol<'arena>,
-    arg: Symbol<'arena>,
-    match_attrs: bool,
-    formals: Formals<'arena>,
-    body: Box<Expr<'arena>>,
+impl<'arena> ExprExt<'arena> for ExprWith {
+    fn bind_vars(&mut self, env: &StaticEnv<'_>) -> NixResult<()> {
+        // Find how far up the enclosing `with` is, if any, so lookups can
+        // continue there.
+        self.prev_with = env
+            .into_iter()
+            .find(|env_level| env_level.env.is_with)
+            .map_or(Level(0), |env_level| env_level.level + Level(1));
+
+        self.attrs.bind_vars(env)?;
+        let new_env = StaticEnv::new(true, Some(env));
+        self.body.bind_vars(&new_env)
+    }
+
+    fn eval(
+        &'arena self,
+        state: &EvalState<'arena>,
+        env: &'arena Env<'arena>,
+    ) -> NixResult<Value<'arena>> {
+        let env = state.arena.alloc_env(Env::new(
+            Some(env),
+            self.prev_with,
+            EnvInner::HasWithExpr(&self.attrs),
+        ));
+        self.body.eval(state, env)
+    }
 }
 
-#[derive(Debug, PartialEq)]
-pub struct ExprLet<'arena> {
-    attrs: Box<ExprAttrs<'arena>>,
-    body: Box<Expr<'arena>>,
+impl<'arena> ExprExt<'arena> for ExprIf {
+    fn bind_vars(&mut self, env: &StaticEnv<'_>) -> NixResult<()> {
+        self.cond.bind_vars(env)?;
+        self.then.bind_vars(env)?;
+        self.else_.bind_vars(env)
+    }
+
+    fn eval(
+        &'arena self,
+        state: &EvalState<'arena>,
+        env: &'arena Env<'arena>,
+    ) -> NixResult<Value<'arena>> {
+        if state.eval_bool(env, &self.cond, &self.pos)? {
+            self.then.eval(state, env)
+        } else {
+            self.else_.eval(state, env)
+        }
+    }
 }
 
-#[derive(Debug, PartialEq)]
-pub struct ExprWith<'arena> {
-    pos: Pos<'arena>,
-    attrs: Box<Expr<'arena>>,
-    body: Box<Expr<'arena>>,
-    prev_with: Level,
+impl<'arena> ExprExt<'arena> for ExprAssert {
+    fn bind_vars(&mut self, env: &StaticEnv<'_>) -> NixResult<()> {
+        self.cond.bind_vars(env)?;
+        self.body.bind_vars(env)
+    }
+
+    fn eval(
+        &'arena self,
+        state: &EvalState<'arena>,
+        env: &'arena Env<'arena>,
+    ) -> NixResult<Value<'arena>> {
+        if !state.eval_bool(env, &self.cond, &self.pos)? {
+            return Err(NixError::AssertionFailed(
+                self.cond_source.clone(),
+                self.pos.to_owned(),
+            ));
+        }
+        self.body.eval(state, env)
+    }
 }
 
-#[derive(Debug, PartialEq)]
-pub struct ExprIf<'arena> {
-    cond: Box<Expr<'arena>>,
-    then: Box<Expr<'arena>>,
-    else_: Box<Expr<'arena>>,
+impl BinOp {
+    /// The operands of a chain of `++`, like `a ++ b ++ c`, with the
+    /// position of the `++` each belongs to. The chain is concatenated in
+    /// one go, rather than copying the intermediate lists. Only chains
+    /// written out in the source are flattened, so concatenating in a
+    /// recursive function still copies the list built so far each time.
+    fn concat_operands<'a>(&'a self, operands: &mut Vec<(&'a Expr, Pos)>) {
+        for e in &[&self.e1, &self.e2] {
+            match &***e {
+                Expr::BinOp(op) if op.kind == OpKind::ConcatLists => op.concat_operands(operands),
+                e => operands.push((e, self.pos.clone())),
+            }
+        }
+    }
 }
 
-#[derive(Debug, PartialEq)]
-pub struct ExprAssert<'arena> {
-    pos: Pos<'arena>,
-    cond: Box<Expr<'arena>>,
-    body: Box<Expr<'arena>>,
+impl<'arena> ExprExt<'arena> for BinOp {
+    fn bind_vars(&mut self, env: &StaticEnv<'_>) -> NixResult<()> {
+        self.e1.bind_vars(env)?;
+        self.e2.bind_vars(env)
+    }
+
+    fn eval(
+        &'arena self,
+        state: &EvalState<'arena>,
+        env: &'arena Env<'arena>,
+    ) -> NixResult<Value<'arena>> {
+        let pos = self.pos.clone();
+        let bool = |expr: &'arena Expr| state.eval_bool(env, expr, &pos);
+        Ok(match self.kind {
+            OpKind::App => {
+                let fun = self.e1.eval(state, env)?;
+                return state.call_function(fun, self.e2.maybe_thunk(state, env), &pos);
+            }
+            OpKind::Eq | OpKind::NEq => {
+                let eq = state.eq_values(self.e1.eval(state, env)?, self.e2.eval(state, env)?)?;
+                Value::Bool(eq == (self.kind == OpKind::Eq))
+            }
+            OpKind::And => Value::Bool(bool(&self.e1)? && bool(&self.e2)?),
+            OpKind::Or => Value::Bool(bool(&self.e1)? || bool(&self.e2)?),
+            OpKind::Impl => Value::Bool(!bool(&self.e1)? || bool(&self.e2)?),
+            OpKind::Update => {
+                let attrs = state.eval_attrs(env, &self.e1, &pos)?;
+                Value::Attrs(attrs.update(state.eval_attrs(env, &self.e2, &pos)?))
+            }
+            OpKind::ConcatLists => {
+                let mut operands = Vec::new();
+                self.concat_operands(&mut operands);
+                let lists = operands
+                    .into_iter()
+                    .map(|(expr, pos)| state.force_list(expr.eval(state, env)?, &pos))
+                    .collect::<NixResult<Vec<_>>>()?;
+                let mut nonempty = lists.iter().filter(|list| !list.is_empty());
+                match (nonempty.next(), nonempty.next()) {
+                    // Nothing needs copying if at most one list has elements.
+                    (None, _) => Value::List(Rc::new([])),
+                    (Some(list), None) => Value::List(list.clone()),
+                    _ => Value::List(lists.iter().flat_map(|list| list.iter().cloned()).collect()),
+                }
+            }
+        })
+    }
 }
 
-#[derive(Debug, PartialEq)]
-pub struct ExprConcatStrings<'arena> {
-    pos: Pos<'arena>,
-    force_string: bool,
-    exprs: Vec<Expr<'arena>>,
+impl<'arena> ExprExt<'arena> for ExprConcatStrings {
+    fn bind_vars(&mut self, env: &StaticEnv<'_>) -> NixResult<()> {
+        self.exprs.iter_mut().try_for_each(|e| e.bind_vars(env))
+    }
+
+    fn eval(
+        &'arena self,
+        state: &EvalState<'arena>,
+        env: &'arena Env<'arena>,
+    ) -> NixResult<Value<'arena>> {
+        let pos = self.pos.clone();
+        let mut values = self.exprs.iter().map(|e| e.eval(state, env));
+        let first = match values.next() {
+            Some(first) => first?,
+            None => return Ok(Value::String(NixString::new(String::new()))),
+        };
+
+        match first {
+            Value::Int(_) | Value::Float(_) if !self.force_string => {
+                let mut sum = first;
+                for value in values {
+                    sum = match (sum, value?) {
+                        (Value::Int(a), Value::Int(b)) => {
+                            Value::Int(a.checked_add(b).ok_or_else(|| {
+                                NixError::Eval(format!(
+                                    "integer overflow in adding {} + {}, at {}",
+                                    a, b, pos
+                                ))
+                            })?)
+                        }
+                        (Value::Int(a), Value::Float(b)) => {
+                            Value::Float(NixFloat::from(a as f64) + b)
+                        }
+                        (Value::Float(a), Value::Int(b)) => {
+                            Value::Float(a + NixFloat::from(b as f64))
+                        }
+                        (Value::Float(a), Value::Float(b)) => Value::Float(a + b),
+                        (sum, value) => {
+                            return Err(NixError::Eval(format!(
+                                "cannot add {} to {}, at {}",
+                                value.show_type(),
+                                sum.show_type(),
+                                pos
+                            )))
+                        }
+                    }
+                }
+                Ok(sum)
+            }
+            first => {
+                let is_path = matches!(first, Value::Path(_)) && !self.force_string;
+                // Paths are copied to the store when they're interpolated or
+                // appended to a string, like upstream. Appended to a path, or
+                // to a set with an `outPath`, they stay where they are.
+                let copy_to_store = self.force_string || matches!(first, Value::String(_));
+                let mut context = NixContext::new();
+                let mut s =
+                    state.coerce_to_string(first, &mut context, false, copy_to_store, &pos)?;
+                for value in values {
+                    s.push_str(&state.coerce_to_string(
+                        value?,
+                        &mut context,
+                        false,
+                        copy_to_store,
+                        &pos,
+                    )?);
+                }
+                if is_path {
+                    if !context.is_empty() {
+                        return Err(NixError::Eval(format!(
+                            "a string that refers to a store path cannot be appended to a path, at {}",
+                            pos
+                        )));
+                    }
+                    Ok(Value::Path(canon_path(&s).into()))
+                } else {
+                    Ok(Value::String(NixString { s, context }))
+                }
+            }
+        }
+    }
 }
 
-#[derive(Debug, PartialEq)]
-pub enum Expr<'arena> {
-    Int(NixInt),
-    Float(NixFloat),
-    String(Symbol<'arena>),
-    Path(PathBuf),
-    Var(ExprVar<'arena>),
-    Select(ExprSelect<'arena>),
-    OpHasAttr(ExprOpHasAttr<'arena>),
-    Attrs(ExprAttrs<'arena>),
-    List(Vec<Expr<'arena>>),
-    Lambda(ExprLambda<'arena>),
-    Let(ExprLet<'arena>),
-    With(ExprWith<'arena>),
-    If(ExprIf<'arena>),
-    Assert(ExprAssert<'arena>),
-    OpNot(Box<Expr<'arena>>),
-    BinOp(BinOp<'arena>),
-    ConcatStrings(ExprConcatStrings<'arena>),
-    Pos(Pos<'arena>),
+impl<'arena> ExprExt<'arena> for Expr {
+    fn bind_vars(&mut self, env: &StaticEnv<'_>) -> NixResult<()> {
+        match self {
+            Expr::Int(_)
+            | Expr::Float(_)
+            | Expr::String(_)
+            | Expr::Path(_)
+            | Expr::Pos(_)
+            | Expr::Error(_) => Ok(()),
+            Expr::Var(var) => var.bind_vars(env),
+            Expr::Select(select) => select.bind_vars(env),
+            Expr::OpHasAttr(has_attr) => has_attr.bind_vars(env),
+            Expr::Attrs(attrs) => attrs.bind_vars(env),
+            Expr::List(elems) => elems.iter_mut().try_for_each(|e| e.bind_vars(env)),
+            Expr::Lambda(lambda) => lambda.bind_vars(env),
+            Expr::Let(let_) => let_.bind_vars(env),
+            Expr::With(with) => with.bind_vars(env),
+            Expr::If(if_) => if_.bind_vars(env),
+            Expr::Assert(assert) => assert.bind_vars(env),
+            Expr::OpNot(not) => not.expr.bind_vars(env),
+            Expr::BinOp(op) => op.bind_vars(env),
+            Expr::ConcatStrings(concat) => concat.bind_vars(env),
+        }
+    }
+
+    fn eval(
+        &'arena self,
+        state: &EvalState<'arena>,
+        env: &'arena Env<'arena>,
+    ) -> NixResult<Value<'arena>> {
+        match self {
+            Expr::Int(n) => Ok(Value::Int(*n)),
+            Expr::Float(n) => Ok(Value::Float(*n)),
+            Expr::String(s) => Ok(Value::String(NixString::new(s.clone()))),
+            Expr::Path(path) => Ok(Value::Path(path.clone())),
+            Expr::Var(var) => var.eval(state, env),
+            Expr::Select(select) => select.eval(state, env),
+            Expr::OpHasAttr(has_attr) => has_attr.eval(state, env),
+            Expr::Attrs(attrs) => attrs.eval(state, env),
+            Expr::List(elems) => Ok(Value::List(
+                elems.iter().map(|e| e.maybe_thunk(state, env)).collect(),
+            )),
+            Expr::Lambda(lambda) => lambda.eval(state, env),
+            Expr::Let(let_) => let_.eval(state, env),
+            Expr::With(with) => with.eval(state, env),
+            Expr::If(if_) => if_.eval(state, env),
+            Expr::Assert(assert) => assert.eval(state, env),
+            Expr::OpNot(not) => Ok(Value::Bool(!state.eval_bool(env, &not.expr, &not.pos)?)),
+            Expr::BinOp(op) => op.eval(state, env),
+            Expr::ConcatStrings(concat) => concat.eval(state, env),
+            Expr::Pos(pos) => Ok(state.mk_pos(pos)),
+            Expr::Error(pos) => Err(NixError::Parse(
+                "cannot evaluate an expression with a syntax error".to_owned(),
+                pos.to_owned(),
+            )),
+        }
+    }
 }
 
-impl<'arena> ExprExt for Expr<'arena> {
-    fn bind_vars<'env>(&mut self, env: &StaticEnv<'env>) -> NixResult<()> {
-        unimplemented!()
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::arena::Arena;
+    use crate::eval::tests::{eval, eval_list};
+    use crate::parser;
+
+    fn parse_print(source: &str) -> String {
+        parser::parse(source, "test.nix", Path::new("/"))
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn show_attrs() {
+        assert_eq!(
+            parse_print(r#"{ b = 1; "a b" = 2; ${c} = 3; "d${e}" = 4; inherit f; }"#),
+            r#"{ "a b" = 2; b = 1; inherit f ; "${c}" = 3; "${("d" + e)}" = 4; }"#
+        );
+        assert_eq!(parse_print("rec { a.b = 1; }"), "rec { a = { b = 1; }; }");
+        assert_eq!(parse_print(r#"a."b c".${d}"#), r#"(a)."b c"."${d}""#);
+    }
+
+    #[test]
+    fn show_lambdas() {
+        assert_eq!(
+            parse_print("{ a, b ? 1 + 2, ... }@args: a"),
+            "({ a, b ? (1 + 2), ... } @ args: a)"
+        );
+        assert_eq!(parse_print("{ ... }: 1"), "({ ... }: 1)");
+    }
+
+    #[test]
+    fn show_scopes() {
+        assert_eq!(
+            parse_print("with a; let b = 1; in b"),
+            "(with a; (let b = 1; in b))"
+        );
+    }
+
+    #[test]
+    fn show_strings() {
+        assert_eq!(
+            parse_print(r#""\"\\\n\r\t${"$"}""#),
+            r#"("\"\\\n\r\t" + "\$")"#
+        );
+    }
+
+    #[test]
+    fn show_numbers() {
+        assert_eq!(
+            parse_print("[ 1 0.1 1.0e30 1.0 ]"),
+            "[ (1) (0.1) (1e+30) (1) ]"
+        );
+    }
+
+    #[test]
+    fn show_applications() {
+        assert_eq!(parse_print("f x"), "(f x)");
+        assert_eq!(parse_print("f x y"), "((f x) y)");
+        assert_eq!(parse_print("f (g x)"), "(f (g x))");
+        assert_eq!(parse_print("-x"), "((__sub 0) x)");
+        assert_eq!(parse_print("a - b"), "((__sub a) b)");
+        assert_eq!(parse_print("a < b"), "((__lessThan a) b)");
+        assert_eq!(parse_print("a > b"), "((__lessThan b) a)");
+    }
+
+    /// Printing gives valid Nix. It doesn't always parse back to the same
+    /// expression, since strings print as chains of `+`.
+    #[test]
+    fn reparse() {
+        for source in &[
+            "{ a, b ? 1, ... }@args: with args; [ (a.b or c) (d ? e) ]",
+            r#"rec { x = "a${b}c"; ${y} = ''  z''; inherit (p) q; }"#,
+            "let f = x: if x then assert x; !x else -x; in f 1 // { }",
+        ] {
+            let printed = parse_print(source);
+            assert!(
+                parser::parse(&printed, "test.nix", Path::new("/")).is_ok(),
+                "{}",
+                printed
+            );
+        }
+    }
+
+    #[test]
+    fn recursive_bindings() {
+        assert_eq!(eval("rec { a = 1; b = a + 1; }"), "{ a = 1; b = 2; }");
+        assert_eq!(
+            eval("{ a = 1; b = a; }"),
+            "error: undefined variable 'a' at '(string):1:14'"
+        );
+        assert_eq!(eval("let a = 1; in rec { a = 2; b = a; }.b"), "2");
+        assert_eq!(eval("let a = b; b = 1; in a"), "1");
+        assert_eq!(eval("let a = 1; in let a = 2; in a"), "2");
+        assert_eq!(eval("let { body = a; a = 1; }"), "1");
+    }
+
+    #[test]
+    fn inherit() {
+        assert_eq!(eval("let inherit ({ x = 1; y = 2; }) x y; in x + y"), "3");
+        assert_eq!(eval("let x = 1; in { inherit x; }"), "{ x = 1; }");
+        assert_eq!(
+            eval("let x = 1; in rec { inherit x; y = x; }"),
+            "{ x = 1; y = 1; }"
+        );
+        assert_eq!(
+            eval("{ inherit missing; }"),
+            "error: undefined variable 'missing' at '(string):1:11'"
+        );
+        assert_eq!(
+            eval("rec { x = 1; inherit x; }"),
+            "error: attribute 'x' at (string):1:22 already defined at (string):1:7"
+        );
+    }
+
+    #[test]
+    fn dynamic_attrs() {
+        assert_eq!(eval(r#"let n = "a"; in { ${n} = 1; }"#), "{ a = 1; }");
+        assert_eq!(eval(r#"let n = "a"; in { ${n} = 1; b = 2; }.a"#), "1");
+        assert_eq!(eval(r#"{ "a${"b"}" = 1; }"#), "{ ab = 1; }");
+        assert_eq!(
+            eval(r#"rec { a = "b"; ${a} = 1; }"#),
+            r#"{ a = "b"; b = 1; }"#
+        );
+        assert_eq!(eval("{ ${null} = 1; }"), "{ }");
+        assert_eq!(
+            eval(r#"let n = "f"; in { ${n} = { a }: a; }.f { }"#),
+            "error: type error: 'f' at (string):1:26 called without required argument 'a', at (string):1:17"
+        );
+        assert_eq!(
+            eval("{ ${1} = 1; }"),
+            "error: type error: value is an integer while a string was expected, at (string):1:3"
+        );
+    }
+
+    #[test]
+    fn duplicate_dynamic_attrs() {
+        assert_eq!(
+            eval(r#"{ ${"a"} = 1; ${"a"} = 2; }"#),
+            "error: dynamic attribute 'a' at (string):1:15 already defined at (string):1:3"
+        );
+        assert_eq!(
+            eval(r#"{ a = 1; ${"a"} = 2; }"#),
+            "error: dynamic attribute 'a' at (string):1:10 already defined at (string):1:3"
+        );
+    }
+
+    #[test]
+    fn nested_attr_paths() {
+        assert_eq!(
+            eval(r#"{ a.b.c = 1; a.b.d = 2; a.e = 3; ${"f"}.g = 4; }"#),
+            "{ a = { b = { c = 1; d = 2; }; e = 3; }; f = { g = 4; }; }"
+        );
+        assert_eq!(
+            eval("rec { a.b = c; c = 1; }"),
+            "{ a = { b = 1; }; c = 1; }"
+        );
+        assert_eq!(
+            eval("{ a = rec { b = 1; }; a.c = b; }"),
+            "{ a = { b = 1; c = 1; }; }"
+        );
+    }
+
+    #[test]
+    fn select_or_default() {
+        assert_eq!(eval("{ a.b = 1; }.a.c or 2"), "2");
+        assert_eq!(eval("{ a = 1; }.a.b or 2"), "2");
+        assert_eq!(eval("{ }.a or (1 + 1)"), "2");
+        assert_eq!(eval(r#"let x = { }; n = "b"; in x.${n} or 3"#), "3");
+    }
+
+    #[test]
+    fn has_attr() {
+        assert_eq!(eval("{ a.b = 1; } ? a.b"), "true");
+        assert_eq!(eval("{ a.b = 1; } ? a.c"), "false");
+        assert_eq!(eval("{ a = 1; } ? a.b"), "false");
+        assert_eq!(eval("1 ? a"), "false");
+    }
+
+    #[test]
+    fn missing_attrs() {
+        assert_eq!(
+            eval("{ a.b = 1; }.a.c"),
+            "error: attribute 'a.c' missing, at (string):1:1"
+        );
+        assert_eq!(
+            eval(r#"let n = "b"; in { a = { }; }.a.${n}"#),
+            "error: attribute 'a.b' missing, at (string):1:17"
+        );
+        assert_eq!(
+            eval("{ a = 1; }.a.b"),
+            "error: type error: value is an integer while a set was expected, at (string):1:1"
+        );
+    }
+
+    #[test]
+    fn boolean_operators() {
+        assert_eq!(eval("true && false"), "false");
+        assert_eq!(eval("true || (1 + true)"), "true");
+        assert_eq!(eval("false && (1 + true)"), "false");
+        assert_eq!(eval("false -> (1 + true)"), "true");
+        assert_eq!(eval("true -> false"), "false");
+        assert_eq!(
+            eval("1 && true"),
+            "error: type error: value is an integer while a Boolean was expected, at (string):1:1"
+        );
+        assert_eq!(
+            eval("true && 1"),
+            "error: type error: value is an integer while a Boolean was expected, at (string):1:1"
+        );
+        assert_eq!(
+            eval("false || 1"),
+            "error: type error: value is an integer while a Boolean was expected, at (string):1:1"
+        );
+    }
+
+    #[test]
+    fn assertions() {
+        assert_eq!(
+            eval("assert 1 == 2; 3"),
+            "error: assertion '1 == 2' failed at (string):1:1"
+        );
+        assert_eq!(
+            eval("let x = 1; in assert x > 2; x"),
+            "error: assertion 'x > 2' failed at (string):1:15"
+        );
+        assert_eq!(
+            eval("assert 1; 3"),
+            "error: type error: value is an integer while a Boolean was expected, at (string):1:1"
+        );
+    }
+
+    #[test]
+    fn update() {
+        assert_eq!(
+            eval("{ a = 1; b = 2; } // { b = 3; c = 4; }"),
+            "{ a = 1; b = 3; c = 4; }"
+        );
+        assert_eq!(eval("{ a.b = 1; } // { a.c = 2; }"), "{ a = { c = 2; }; }");
+        assert_eq!(eval("{ } // { a = 1; }"), "{ a = 1; }");
+        assert_eq!(eval("{ a = 1; } // { }"), "{ a = 1; }");
+        assert_eq!(
+            eval("{ a = 1; } // 1"),
+            "error: type error: value is an integer while a set was expected, at (string):1:1"
+        );
     }
 
-    fn eval<'a>(&'a self, state: &EvalState, env: &Env) -> NixResult<&'a Value> {
-        unimplemented!()
+    #[test]
+    fn concat_lists() {
+        assert_eq!(eval("[ 1 ] ++ [ 2 3 ]"), "[ 1 2 3 ]");
+        assert_eq!(eval("[ 1 ] ++ [ 2 ] ++ [ 3 ]"), "[ 1 2 3 ]");
+        assert_eq!(eval("[ ] ++ [ 1 ]"), "[ 1 ]");
+        assert_eq!(eval("[ ] ++ [ ]"), "[ ]");
+        assert_eq!(
+            eval("[ 1 ] ++ 2"),
+            "error: type error: value is an integer while a list was expected, at (string):1:1"
+        );
     }
 
-    fn maybe_thunk<'a>(&'a self, state: &EvalState, env: &Env) -> &'a Value {
-        unimplemented!()
+    #[test]
+    fn concat_lists_shares_single_list() {
+        let arena = Arena::new();
+        let state = EvalState::new(&arena);
+        let elems = eval_list(&state, "let a = [ 1 2 ]; in [ a ([ ] ++ a ++ [ ]) ]");
+        let lists = elems
+            .into_iter()
+            .map(|elem| state.force_list(elem, &Pos::Undefined).unwrap())
+            .collect::<Vec<_>>();
+        assert!(Rc::ptr_eq(&lists[0], &lists[1]));
     }
 }
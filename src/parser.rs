@@ -0,0 +1,2117 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_until, take_while},
+    character::complete::multispace1,
+    combinator::recognize,
+    error::ErrorKind,
+    multi::many0_count,
+    sequence::{delimited, preceded},
+    IResult,
+};
+
+use crate::env::Level;
+use crate::err::{NixError, NixResult};
+use crate::nix_expr::{
+    AttrName, AttrPath, BinOp, Expr, ExprAssert, ExprAttrs, ExprConcatStrings, ExprIf, ExprLambda,
+    ExprLet, ExprOpHasAttr, ExprOpNot, ExprSelect, ExprVar, ExprWith, Formal, Formals, OpKind,
+};
+use crate::pos::{KnownPos, Pos};
+use crate::symbol_table::Symbol;
+use crate::value::NixFloat;
+
+type PResult<'s, T> = IResult<&'s str, T>;
+type PError<'s> = nom::Err<(&'s str, ErrorKind)>;
+
+pub(crate) const KEYWORDS: &[&str] = &[
+    "if", "then", "else", "assert", "with", "let", "in", "rec", "inherit", "or",
+];
+
+/// Punctuation tokens, longest first so the first prefix match is the longest
+/// one.
+const PUNCTUATION: &[&str] = &[
+    "...", "==", "!=", "<=", ">=", "&&", "||", "->", "//", "++", "${", "''", "(", ")", "[", "]",
+    "{", "}", ":", ";", ",", "@", "?", ".", "=", "!", "<", ">", "+", "-", "*", "/", "\"",
+];
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Assoc {
+    Left,
+    Right,
+    None,
+}
+
+/// Tokens which can start an expression.
+const EXPRESSION_START: &[&str] = &[
+    "if", "assert", "with", "let", "rec", "(", "[", "{", "\"", "''", "!", "-",
+];
+
+/// Tokens which can end an expression, where a parser recovering from a
+/// broken one picks up again.
+const EXPR_END: &[&str] = &[")", "]", "}", ";", "in", "then", "else"];
+
+pub(crate) const NOT_PREC: u8 = 7;
+pub(crate) const NEGATE_PREC: u8 = 12;
+
+/// Binary operators with their precedence and associativity, following
+/// upstream's `parser.y`. `!` and unary `-` slot in at `NOT_PREC` and
+/// `NEGATE_PREC`.
+pub(crate) const BINARY_OPS: &[(&str, u8, Assoc)] = &[
+    ("->", 1, Assoc::Right),
+    ("||", 2, Assoc::Left),
+    ("&&", 3, Assoc::Left),
+    ("==", 4, Assoc::None),
+    ("!=", 4, Assoc::None),
+    ("<", 5, Assoc::None),
+    (">", 5, Assoc::None),
+    ("<=", 5, Assoc::None),
+    (">=", 5, Assoc::None),
+    ("//", 6, Assoc::Right),
+    ("+", 8, Assoc::Left),
+    ("-", 8, Assoc::Left),
+    ("*", 9, Assoc::Left),
+    ("/", 9, Assoc::Left),
+    ("++", 10, Assoc::Right),
+    ("?", 11, Assoc::None),
+];
+
+/// Parse a Nix expression. `file` is used for positions and error messages,
+/// and relative path literals are resolved against `base_path`, usually the
+/// directory containing `file`.
+pub fn parse(source: &str, file: &str, base_path: &Path) -> NixResult<Expr> {
+    Parser::new(source, file, base_path, false).parse()
+}
+
+/// Parse a Nix expression like [`parse`], but recover from errors so that
+/// every error in the source is reported instead of only the first.
+///
+/// Broken bindings and list elements are left out of the result, and a
+/// missing operand is replaced by an [`Expr::Error`], so the expression is
+/// only partial if there are errors. It is missing entirely if even the
+/// outermost expression couldn't be parsed.
+pub fn parse_recovering(
+    source: &str,
+    file: &str,
+    base_path: &Path,
+) -> (Option<Expr>, Vec<NixError>) {
+    Parser::new(source, file, base_path, true).parse_recovering()
+}
+
+/// The kinds of "word-like" tokens, which are lexed by maximal munch.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum WordKind {
+    Id,
+    Int,
+    Float,
+    /// A path segment followed by an antiquotation, like `./${`.
+    PathStart,
+    /// `~/${`
+    HPathStart,
+    Path,
+    /// A path in the home directory, like `~/foo`.
+    HPath,
+    /// A search path lookup, like `<nixpkgs>`.
+    SPath,
+    Uri,
+}
+
+fn is_id_start(c: u8) -> bool {
+    c.is_ascii_alphabetic() || c == b'_'
+}
+
+fn is_id_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_' || c == b'\'' || c == b'-'
+}
+
+pub(crate) fn is_path_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"._-+".contains(&c)
+}
+
+fn is_uri_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"%/?:@&=+$,-_.!~*'".contains(&c)
+}
+
+pub(crate) fn count(b: &[u8], from: usize, pred: impl Fn(u8) -> bool) -> usize {
+    b.get(from..)
+        .map_or(0, |rest| rest.iter().take_while(|&&c| pred(c)).count())
+}
+
+/// `[a-zA-Z_][a-zA-Z0-9_'-]*`
+fn lex_id(i: &str) -> usize {
+    let b = i.as_bytes();
+    match b.first() {
+        Some(&c) if is_id_start(c) => 1 + count(b, 1, is_id_char),
+        _ => 0,
+    }
+}
+
+/// `[0-9]+`
+fn lex_int(i: &str) -> usize {
+    count(i.as_bytes(), 0, |c| c.is_ascii_digit())
+}
+
+/// `(([1-9][0-9]*\.[0-9]*)|(0?\.[0-9]+))([Ee][+-]?[0-9]+)?`
+fn lex_float(i: &str) -> usize {
+    let b = i.as_bytes();
+    let digits = |from| count(b, from, |c| c.is_ascii_digit());
+    let mut n = match b.first() {
+        Some(b'1'..=b'9') => {
+            let n = 1 + digits(1);
+            if b.get(n) != Some(&b'.') {
+                return 0;
+            }
+            n + 1 + digits(n + 1)
+        }
+        _ => {
+            let n = if b.first() == Some(&b'0') { 1 } else { 0 };
+            if b.get(n) != Some(&b'.') || digits(n + 1) == 0 {
+                return 0;
+            }
+            n + 1 + digits(n + 1)
+        }
+    };
+    if let Some(b'e') | Some(b'E') = b.get(n) {
+        let mut m = n + 1;
+        if let Some(b'+') | Some(b'-') = b.get(m) {
+            m += 1;
+        }
+        if digits(m) > 0 {
+            n = m + digits(m);
+        }
+    }
+    n
+}
+
+/// `{PATH_CHAR}*(\/{PATH_CHAR}+)+\/?`
+pub(crate) fn lex_path(i: &str) -> usize {
+    lex_path_segments(i, count(i.as_bytes(), 0, is_path_char))
+}
+
+/// `\~(\/{PATH_CHAR}+)+\/?`
+fn lex_hpath(i: &str) -> usize {
+    if i.starts_with('~') {
+        lex_path_segments(i, 1)
+    } else {
+        0
+    }
+}
+
+/// `(\/{PATH_CHAR}+)+\/?`, starting at offset `n`.
+fn lex_path_segments(i: &str, mut n: usize) -> usize {
+    let b = i.as_bytes();
+    let mut segments = 0;
+    while b.get(n) == Some(&b'/') {
+        let segment = count(b, n + 1, is_path_char);
+        if segment == 0 {
+            break;
+        }
+        n += 1 + segment;
+        segments += 1;
+    }
+    if segments == 0 {
+        return 0;
+    }
+    if b.get(n) == Some(&b'/') {
+        n += 1;
+    }
+    n
+}
+
+/// `{PATH_CHAR}*\/`
+pub(crate) fn lex_path_seg(i: &str) -> usize {
+    let b = i.as_bytes();
+    let n = count(b, 0, is_path_char);
+    if b.get(n) == Some(&b'/') {
+        n + 1
+    } else {
+        0
+    }
+}
+
+/// `{PATH_SEG}\$\{`
+fn lex_path_start(i: &str) -> usize {
+    match lex_path_seg(i) {
+        n if n > 0 && i[n..].starts_with("${") => n + 2,
+        _ => 0,
+    }
+}
+
+/// `\~\/\$\{`
+fn lex_hpath_start(i: &str) -> usize {
+    if i.starts_with("~/${") {
+        4
+    } else {
+        0
+    }
+}
+
+/// `\<{PATH_CHAR}+(\/{PATH_CHAR}+)*\>`
+fn lex_spath(i: &str) -> usize {
+    let b = i.as_bytes();
+    if b.first() != Some(&b'<') {
+        return 0;
+    }
+    let mut n = 1 + count(b, 1, is_path_char);
+    if n == 1 {
+        return 0;
+    }
+    while b.get(n) == Some(&b'/') {
+        let segment = count(b, n + 1, is_path_char);
+        if segment == 0 {
+            return 0;
+        }
+        n += 1 + segment;
+    }
+    if b.get(n) == Some(&b'>') {
+        n + 1
+    } else {
+        0
+    }
+}
+
+/// `[a-zA-Z][a-zA-Z0-9\+\-\.]*\:{URI_CHAR}+`
+fn lex_uri(i: &str) -> usize {
+    let b = i.as_bytes();
+    if !b.first().is_some_and(u8::is_ascii_alphabetic) {
+        return 0;
+    }
+    let scheme = 1 + count(b, 1, |c| c.is_ascii_alphanumeric() || b"+-.".contains(&c));
+    if b.get(scheme) != Some(&b':') {
+        return 0;
+    }
+    match count(b, scheme + 1, is_uri_char) {
+        0 => 0,
+        rest => scheme + 1 + rest,
+    }
+}
+
+/// Lex the longest word-like token at the start of `i`, preferring earlier
+/// kinds on ties like flex does.
+pub(crate) fn lex_word(i: &str) -> Option<(WordKind, usize)> {
+    let candidates = [
+        (WordKind::Id, lex_id(i)),
+        (WordKind::Int, lex_int(i)),
+        (WordKind::Float, lex_float(i)),
+        (WordKind::PathStart, lex_path_start(i)),
+        (WordKind::HPathStart, lex_hpath_start(i)),
+        (WordKind::Path, lex_path(i)),
+        (WordKind::HPath, lex_hpath(i)),
+        (WordKind::SPath, lex_spath(i)),
+        (WordKind::Uri, lex_uri(i)),
+    ];
+    let mut best = None;
+    for &(kind, len) in candidates.iter() {
+        match best {
+            Some((_, best_len)) if best_len >= len => {}
+            _ if len == 0 => {}
+            _ => best = Some((kind, len)),
+        }
+    }
+    best
+}
+
+/// The longest run of plain text in an indented string,
+/// `([^$']|\$[^{']|'[^'$])+`.
+pub(crate) fn lex_ind_string_raw(i: &str) -> usize {
+    let b = i.as_bytes();
+    let mut n = 0;
+    while n < b.len() {
+        match (b[n], b.get(n + 1)) {
+            (b'$', Some(&c)) if c != b'{' && c != b'\'' => n += 2,
+            (b'\'', Some(&c)) if c != b'\'' && c != b'$' => n += 2,
+            (b'$', _) | (b'\'', _) => break,
+            _ => n += 1,
+        }
+    }
+    n
+}
+
+/// `base_path` made absolute, for resolving relative path literals against.
+pub(crate) fn absolute_base_path(base_path: &Path) -> PathBuf {
+    // Joining an absolute path onto the working directory leaves it
+    // unchanged.
+    std::env::current_dir()
+        .map(|cwd| cwd.join(base_path))
+        .unwrap_or_else(|_| base_path.to_owned())
+}
+
+/// Resolve the leading segment of a path literal, like `./foo` or `~/foo`,
+/// to an absolute path. Fails if the home directory is needed but unknown.
+pub(crate) fn resolve_path(first: &str, base_path: &Path) -> Result<String, String> {
+    if let Some(rest) = first.strip_prefix('~') {
+        return match std::env::var("HOME") {
+            Ok(home) => Ok(home + rest),
+            Err(_) => Err(format!("cannot resolve '{}': HOME is not set", first)),
+        };
+    }
+    let mut path = canon_path(&base_path.join(first).to_string_lossy());
+    // Keep the trailing slash of a leading segment like `./${x}`.
+    if first.ends_with('/') && first.len() > 1 {
+        path.push('/');
+    }
+    Ok(path)
+}
+
+pub(crate) fn var(pos: Pos, name: &str) -> Expr {
+    Expr::Var(ExprVar::new(pos, Symbol::new(name)))
+}
+
+/// Desugar `e1 op e2` into a call of the builtin `fun`, like upstream.
+pub(crate) fn call_builtin(fun: &str, pos: Pos, e1: Expr, e2: Expr) -> Expr {
+    let app = BinOp::new(OpKind::App, pos.clone(), var(pos.clone(), fun), e1);
+    Expr::BinOp(BinOp::new(OpKind::App, pos, Expr::BinOp(app), e2))
+}
+
+/// `!e`, where `pos` is the position of `e`.
+pub(crate) fn not(pos: Pos, e: Expr) -> Expr {
+    Expr::OpNot(ExprOpNot {
+        pos,
+        expr: Box::new(e),
+    })
+}
+
+/// The expression for the binary operator `op`, desugared like upstream.
+pub(crate) fn binary_op_expr(op: &str, pos: Pos, lhs: Expr, rhs: Expr) -> Expr {
+    match op {
+        "->" => Expr::BinOp(BinOp::new(OpKind::Impl, pos, lhs, rhs)),
+        "||" => Expr::BinOp(BinOp::new(OpKind::Or, pos, lhs, rhs)),
+        "&&" => Expr::BinOp(BinOp::new(OpKind::And, pos, lhs, rhs)),
+        "==" => Expr::BinOp(BinOp::new(OpKind::Eq, pos, lhs, rhs)),
+        "!=" => Expr::BinOp(BinOp::new(OpKind::NEq, pos, lhs, rhs)),
+        "<" => call_builtin("__lessThan", pos, lhs, rhs),
+        ">" => call_builtin("__lessThan", pos, rhs, lhs),
+        "<=" => not(pos.clone(), call_builtin("__lessThan", pos, rhs, lhs)),
+        ">=" => not(pos.clone(), call_builtin("__lessThan", pos, lhs, rhs)),
+        "//" => Expr::BinOp(BinOp::new(OpKind::Update, pos, lhs, rhs)),
+        "+" => Expr::ConcatStrings(ExprConcatStrings {
+            pos,
+            force_string: false,
+            exprs: vec![lhs, rhs],
+        }),
+        "-" => call_builtin("__sub", pos, lhs, rhs),
+        "*" => call_builtin("__mul", pos, lhs, rhs),
+        "/" => call_builtin("__div", pos, lhs, rhs),
+        "++" => Expr::BinOp(BinOp::new(OpKind::ConcatLists, pos, lhs, rhs)),
+        _ => unreachable!("unknown operator {}", op),
+    }
+}
+
+/// Remove the common leading indentation from the lines of an indented
+/// string. Escapes and antiquotations end a line's indentation, and a
+/// trailing line of only spaces is dropped.
+pub(crate) fn strip_indentation(pos: Pos, parts: Vec<IndStringPart>) -> Expr {
+    if parts.is_empty() {
+        return Expr::String(String::new());
+    }
+
+    // Figure out the minimum indentation. Lines containing only
+    // whitespace don't count.
+    let mut at_start_of_line = true;
+    let mut min_indent = usize::MAX;
+    let mut cur_indent = 0;
+    for part in &parts {
+        match part {
+            IndStringPart::Raw(s) => {
+                for c in s.chars() {
+                    if at_start_of_line {
+                        if c == ' ' {
+                            cur_indent += 1;
+                        } else if c == '\n' {
+                            cur_indent = 0;
+                        } else {
+                            at_start_of_line = false;
+                            min_indent = min_indent.min(cur_indent);
+                        }
+                    } else if c == '\n' {
+                        at_start_of_line = true;
+                        cur_indent = 0;
+                    }
+                }
+            }
+            IndStringPart::Escaped(_) | IndStringPart::Antiquote(_) => {
+                if at_start_of_line {
+                    at_start_of_line = false;
+                    min_indent = min_indent.min(cur_indent);
+                }
+            }
+        }
+    }
+
+    // Strip spaces from each line.
+    let mut exprs = Vec::with_capacity(parts.len());
+    let mut at_start_of_line = true;
+    let mut cur_dropped = 0;
+    let last = parts.len() - 1;
+    for (n, part) in parts.into_iter().enumerate() {
+        let s = match part {
+            IndStringPart::Raw(s) => s,
+            IndStringPart::Escaped(c) => {
+                at_start_of_line = false;
+                cur_dropped = 0;
+                exprs.push(Expr::String(c.to_string()));
+                continue;
+            }
+            IndStringPart::Antiquote(expr) => {
+                at_start_of_line = false;
+                cur_dropped = 0;
+                exprs.push(expr);
+                continue;
+            }
+        };
+
+        let mut stripped = String::with_capacity(s.len());
+        for c in s.chars() {
+            if at_start_of_line {
+                if c == ' ' {
+                    if cur_dropped >= min_indent {
+                        stripped.push(c);
+                    }
+                    cur_dropped += 1;
+                } else if c == '\n' {
+                    cur_dropped = 0;
+                    stripped.push(c);
+                } else {
+                    at_start_of_line = false;
+                    cur_dropped = 0;
+                    stripped.push(c);
+                }
+            } else {
+                stripped.push(c);
+                if c == '\n' {
+                    at_start_of_line = true;
+                }
+            }
+        }
+
+        // Remove the last line if it is empty and consists only of
+        // spaces.
+        if n == last {
+            if let Some(newline) = stripped.rfind('\n') {
+                if stripped[newline + 1..].chars().all(|c| c == ' ') {
+                    stripped.truncate(newline + 1);
+                }
+            }
+        }
+
+        exprs.push(Expr::String(stripped));
+    }
+
+    // A single string doesn't need to be concatenated.
+    if let [Expr::String(_)] = exprs.as_slice() {
+        return exprs.pop().unwrap();
+    }
+    Expr::ConcatStrings(ExprConcatStrings {
+        pos,
+        force_string: true,
+        exprs,
+    })
+}
+
+/// Spaces and a newline directly after the `''` opening an indented string
+/// are skipped.
+pub(crate) fn skip_ind_string_start(i: &str) -> &str {
+    let spaces = i.len() - i.trim_start_matches(' ').len();
+    i[spaces..].strip_prefix('\n').unwrap_or(i)
+}
+
+/// Split the text of an indented string up to its end or the next
+/// antiquotation into parts, the same way upstream's lexer splits it, since
+/// that affects which trailing line `strip_indentation` drops. Returns how
+/// much was consumed.
+pub(crate) fn ind_string_parts(i: &str, parts: &mut Vec<IndStringPart>) -> usize {
+    let mut n = 0;
+    loop {
+        let rest = &i[n..];
+        let raw = lex_ind_string_raw(rest);
+        if raw > 0 {
+            parts.push(IndStringPart::Raw(rest[..raw].to_owned()));
+            n += raw;
+        } else if rest.starts_with("'''") {
+            parts.push(IndStringPart::Raw("''".to_owned()));
+            n += 3;
+        } else if rest.starts_with("''$") {
+            parts.push(IndStringPart::Raw("$".to_owned()));
+            n += 3;
+        } else if let Some(escaped) = rest.strip_prefix("''\\").and_then(|r| r.chars().next()) {
+            parts.push(IndStringPart::Escaped(unescape(escaped)));
+            n += 3 + escaped.len_utf8();
+        } else if rest.is_empty() || rest.starts_with("${") || rest.starts_with("''") {
+            return n;
+        } else {
+            // A lone `$` or `'`.
+            parts.push(IndStringPart::Raw(rest[..1].to_owned()));
+            n += 1;
+        }
+    }
+}
+
+/// Unescape the text of a double-quoted string up to its end or the next
+/// antiquotation, appending it to `s`. Returns how much was consumed.
+pub(crate) fn unescape_string(i: &str, s: &mut String) -> usize {
+    let mut n = 0;
+    loop {
+        let mut chars = i[n..].chars();
+        let c = match chars.next() {
+            Some(c) => c,
+            None => return n,
+        };
+        match (c, chars.next()) {
+            ('"', _) | ('$', Some('{')) | ('\\', None) => return n,
+            ('\\', Some(escaped)) => {
+                s.push(unescape(escaped));
+                n += 1 + escaped.len_utf8();
+            }
+            // `$$` is literal, so `$${` doesn't start an antiquotation.
+            ('$', Some('$')) => {
+                s.push_str("$$");
+                n += 2;
+            }
+            // Normalise CR and CR/LF into LF.
+            ('\r', Some('\n')) => {
+                s.push('\n');
+                n += 2;
+            }
+            ('\r', _) => {
+                s.push('\n');
+                n += 1;
+            }
+            (c, _) => {
+                s.push(c);
+                n += c.len_utf8();
+            }
+        }
+    }
+}
+
+/// Lexically normalise an absolute path, removing `.` and `..` components
+/// and redundant slashes, without touching the filesystem.
+pub(crate) fn canon_path(path: &str) -> String {
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    format!("/{}", components.join("/"))
+}
+
+/// The character a backslash escape like `\n` stands for.
+fn unescape(c: char) -> char {
+    match c {
+        'n' => '\n',
+        'r' => '\r',
+        't' => '\t',
+        c => c,
+    }
+}
+
+pub(crate) fn punct(i: &str) -> Option<&'static str> {
+    PUNCTUATION.iter().find(|p| i.starts_with(*p)).copied()
+}
+
+pub(crate) fn line_comment(i: &str) -> IResult<&str, &str> {
+    recognize(preceded(tag("#"), take_while(|c| c != '\n' && c != '\r')))(i)
+}
+
+pub(crate) fn block_comment(i: &str) -> IResult<&str, &str> {
+    recognize(delimited(tag("/*"), take_until("*/"), tag("*/")))(i)
+}
+
+/// Whitespace and comments.
+fn trivia(i: &str) -> IResult<&str, &str> {
+    recognize(many0_count(alt((multispace1, line_comment, block_comment))))(i)
+}
+
+/// The length of the token at the start of `i`, treating whole strings as
+/// single tokens. Used to skip over broken code.
+fn token_len(i: &str) -> Option<usize> {
+    if i.is_empty() {
+        return None;
+    }
+    if i.starts_with('"') {
+        let mut chars = i.char_indices().skip(1);
+        while let Some((n, c)) = chars.next() {
+            match c {
+                '\\' => {
+                    chars.next();
+                }
+                '"' => return Some(n + 1),
+                _ => {}
+            }
+        }
+        return Some(i.len());
+    }
+    if i.starts_with("''") {
+        let mut n = 2;
+        while let Some(end) = i[n..].find("''") {
+            n += end + 2;
+            match i[n..].chars().next() {
+                Some('\'') | Some('$') => n += 1,
+                Some('\\') => n += 1 + i[n + 1..].chars().next().map_or(0, char::len_utf8),
+                _ => return Some(n),
+            }
+        }
+        return Some(i.len());
+    }
+    lex_word(i)
+        .map(|(_, len)| len)
+        .or_else(|| punct(i).map(str::len))
+        .or_else(|| i.chars().next().map(char::len_utf8))
+}
+
+/// Turn a recoverable parse error into `None`, so alternatives can be tried,
+/// while still propagating fatal errors.
+fn attempt<'s, T>(result: PResult<'s, T>) -> Result<Option<(&'s str, T)>, PError<'s>> {
+    match result {
+        Ok(ok) => Ok(Some(ok)),
+        Err(nom::Err::Error(_)) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Something the parser was looking for when it failed, used to build
+/// "unexpected X, expecting Y" messages.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Expected {
+    Token(&'static str),
+    Identifier,
+    Expression,
+    EndOfFile,
+}
+
+impl Display for Expected {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Expected::Token(token) => write!(f, "'{}'", token),
+            Expected::Identifier => write!(f, "identifier"),
+            Expected::Expression => write!(f, "expression"),
+            Expected::EndOfFile => write!(f, "end of file"),
+        }
+    }
+}
+
+/// A piece of an indented string, before indentation is stripped.
+pub(crate) enum IndStringPart {
+    /// Text from the source, including `''$`, `'''` and lone `$` or `'`.
+    Raw(String),
+    /// A `''\x` escape, which isn't subject to indentation stripping.
+    Escaped(char),
+    Antiquote(Expr),
+}
+
+struct Parser<'s> {
+    source: &'s str,
+    /// Shared by every position in the expression.
+    file: Rc<str>,
+    /// The directory relative paths are resolved against.
+    base_path: PathBuf,
+    /// Byte offsets of the start of each line.
+    line_starts: Vec<usize>,
+    /// The furthest offset at which a token failed to match, and what was
+    /// expected there. Syntax errors are reported at this point.
+    furthest: RefCell<(usize, Vec<Expected>)>,
+    /// Whether to skip past errors and keep parsing, rather than stopping at
+    /// the first one.
+    recovering: bool,
+    /// Errors which aren't plain syntax errors, like duplicate attributes,
+    /// and, when recovering, every syntax error skipped past.
+    errors: RefCell<Vec<NixError>>,
+    /// The offsets of the syntax errors in `errors`, so each position is
+    /// only reported once.
+    syntax_error_offsets: RefCell<HashSet<usize>>,
+}
+
+impl<'s> Parser<'s> {
+    fn new(source: &'s str, file: &str, base_path: &Path, recovering: bool) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(offset, _)| offset + 1))
+            .collect();
+        Parser {
+            source,
+            file: Rc::from(file),
+            base_path: absolute_base_path(base_path),
+            line_starts,
+            furthest: RefCell::new((0, Vec::new())),
+            recovering,
+            errors: RefCell::new(Vec::new()),
+            syntax_error_offsets: RefCell::new(HashSet::new()),
+        }
+    }
+
+    fn parse(&self) -> NixResult<Expr> {
+        let result = self.expr(self.source).and_then(|(i, expr)| {
+            let (i, _) = trivia(i)?;
+            if i.is_empty() {
+                Ok((i, expr))
+            } else {
+                self.expect(i, Expected::EndOfFile)
+            }
+        });
+        match result {
+            Ok((_, expr)) => Ok(expr),
+            Err(_) => {
+                let mut errors = self.errors.borrow_mut();
+                Err(if errors.is_empty() {
+                    self.syntax_error()
+                } else {
+                    errors.remove(0)
+                })
+            }
+        }
+    }
+
+    fn parse_recovering(&self) -> (Option<Expr>, Vec<NixError>) {
+        let expr = match self.expr(self.source) {
+            Ok((i, expr)) => {
+                let (i, _) = trivia(i).unwrap_or((i, ""));
+                if !i.is_empty() {
+                    let _: PResult<()> = self.expect(i, Expected::EndOfFile);
+                    self.recover(i, &[], &[]);
+                }
+                Some(expr)
+            }
+            Err(_) => {
+                self.recover(self.source, &[], &[]);
+                None
+            }
+        };
+
+        // The parser can backtrack over an error and find it again.
+        let mut errors = self.errors.take();
+        let mut seen = HashSet::new();
+        errors.retain(|err| seen.insert(err.to_string()));
+        (expr, errors)
+    }
+
+    fn offset(&self, i: &str) -> usize {
+        self.source.len() - i.len()
+    }
+
+    fn pos_at(&self, offset: usize) -> Pos {
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(line) => line - 1,
+        };
+        Pos::Known(KnownPos {
+            file: self.file.clone(),
+            line: line + 1,
+            column: self.source[self.line_starts[line]..offset].chars().count() + 1,
+        })
+    }
+
+    fn pos(&self, i: &str) -> Pos {
+        self.pos_at(self.offset(i))
+    }
+
+    fn syntax_error(&self) -> NixError {
+        let (offset, expected) = &*self.furthest.borrow();
+        let rest = &self.source[*offset..];
+        let unexpected = if rest.is_empty() {
+            "end of file".to_owned()
+        } else if let Some((_, len)) = lex_word(rest) {
+            format!("'{}'", &rest[..len])
+        } else if let Some(p) = punct(rest) {
+            format!("'{}'", p)
+        } else {
+            format!("'{}'", rest.chars().next().unwrap())
+        };
+        // Identifiers and tokens which start an expression are implied by
+        // expecting one.
+        let mut expected: Vec<_> = if expected.contains(&Expected::Expression) {
+            expected
+                .iter()
+                .filter(|e| match e {
+                    Expected::Identifier => false,
+                    Expected::Token(t) => !EXPRESSION_START.contains(t),
+                    _ => true,
+                })
+                .collect()
+        } else {
+            expected.iter().collect()
+        };
+        // Binary operators can follow almost anything, so they're the least
+        // helpful suggestions, and long lists of alternatives are cut short.
+        expected.sort_by_key(
+            |e| matches!(e, Expected::Token(t) if BINARY_OPS.iter().any(|(op, _, _)| op == t)),
+        );
+        let shown: Vec<_> = expected.iter().take(4).map(ToString::to_string).collect();
+        let msg = if expected.is_empty() {
+            format!("syntax error, unexpected {}", unexpected)
+        } else if expected.len() > shown.len() {
+            format!(
+                "syntax error, expected {}, ..., found {}",
+                shown.join(", "),
+                unexpected
+            )
+        } else {
+            format!(
+                "syntax error, expected {}, found {}",
+                shown.join(" or "),
+                unexpected
+            )
+        };
+        NixError::Parse(msg, self.pos_at(*offset).to_owned())
+    }
+
+    /// Record the syntax error at the furthest failure point, unless there
+    /// already is one there, like when a bracket is closed by mistake and
+    /// the enclosing expression trips over it too.
+    fn report_syntax_error(&self) {
+        let offset = self.furthest.borrow().0;
+        if self.syntax_error_offsets.borrow_mut().insert(offset) {
+            self.errors.borrow_mut().push(self.syntax_error());
+        }
+    }
+
+    /// Record the syntax error at the furthest failure point and skip past
+    /// it, returning where to resume parsing.
+    ///
+    /// Tokens are skipped up to the first of `consume`, which is skipped
+    /// too, or `stop`, which isn't, outside of any brackets.
+    fn recover(&self, i: &'s str, consume: &[&str], stop: &[&str]) -> &'s str {
+        self.report_syntax_error();
+
+        let from = self.offset(i).max(self.furthest.borrow().0);
+        let mut i = &self.source[from..];
+        let mut depth = 0_usize;
+        while let Some((token, rest)) = self.peek(i) {
+            if depth == 0 && stop.contains(&token) {
+                i = &self.source[self.offset(rest) - token.len()..];
+                break;
+            }
+            i = rest;
+            if depth == 0 && consume.contains(&token) {
+                break;
+            }
+            match token {
+                "(" | "[" | "{" | "${" => depth += 1,
+                ")" | "]" | "}" => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+
+        *self.furthest.borrow_mut() = (self.offset(i), Vec::new());
+        i
+    }
+
+    /// Record the syntax error at the furthest failure point and stand in
+    /// an error node for the expression which should have been at `i`,
+    /// without skipping anything.
+    fn missing(&self, i: &'s str) -> Expr {
+        self.report_syntax_error();
+        let (i, _) = trivia(i).unwrap_or((i, ""));
+        let mut furthest = self.furthest.borrow_mut();
+        *furthest = (self.offset(i).max(furthest.0), Vec::new());
+        Expr::Error(self.pos(i))
+    }
+
+    /// An expression which has to be at `i`, like the body of a lambda or
+    /// the inside of brackets. When recovering, a broken one is skipped up
+    /// to the next token which could end it and stood in for by an error
+    /// node, so the code around it is kept.
+    fn required_expr(&self, i: &'s str) -> PResult<'s, Expr> {
+        match self.expr(i) {
+            Err(nom::Err::Error(_)) if self.recovering => {
+                let (i, _) = trivia(i)?;
+                let pos = self.pos(i);
+                Ok((self.recover(i, &[], EXPR_END), Expr::Error(pos)))
+            }
+            result => result,
+        }
+    }
+
+    /// Skip a closing bracket `t`, or when recovering, whatever is in the
+    /// way of it.
+    fn close(&self, i: &'s str, t: &'static str) -> PResult<'s, ()> {
+        match self.sym(i, t) {
+            Ok((i, _)) => Ok((i, ())),
+            Err(nom::Err::Error(_)) if self.recovering => {
+                let stop: Vec<_> = [")", "]", "}", ";", "in"]
+                    .iter()
+                    .copied()
+                    .filter(|&s| s != t)
+                    .collect();
+                Ok((self.recover(i, &[t], &stop), ()))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// The next token and what follows it, without recording anything
+    /// about what was expected.
+    fn peek(&self, i: &'s str) -> Option<(&'s str, &'s str)> {
+        let i = trivia(i).map_or(i, |(i, _)| i);
+        token_len(i).map(|len| (&i[..len], &i[len..]))
+    }
+
+    /// Fail with a recoverable error, recording what was expected at `i`.
+    fn expect<T>(&self, i: &'s str, what: Expected) -> PResult<'s, T> {
+        let offset = self.offset(i);
+        let mut furthest = self.furthest.borrow_mut();
+        if offset > furthest.0 {
+            *furthest = (offset, vec![what]);
+        } else if offset == furthest.0 && !furthest.1.contains(&what) {
+            furthest.1.push(what);
+        }
+        Err(nom::Err::Error((i, ErrorKind::Tag)))
+    }
+
+    /// Report an error which isn't a syntax error, like a duplicate
+    /// attribute. This aborts parsing unless the parser is recovering, in
+    /// which case the caller carries on as best it can.
+    fn error(&self, i: &'s str, err: NixError) -> Result<(), PError<'s>> {
+        self.errors.borrow_mut().push(err);
+        if self.recovering {
+            Ok(())
+        } else {
+            Err(nom::Err::Failure((i, ErrorKind::Verify)))
+        }
+    }
+
+    fn parse_error(&self, i: &'s str, pos: Pos, msg: String) -> Result<(), PError<'s>> {
+        self.error(i, NixError::Parse(msg, pos.to_owned()))
+    }
+
+    /// Match the punctuation token `t`.
+    fn sym(&self, i: &'s str, t: &'static str) -> PResult<'s, &'s str> {
+        let (i, _) = trivia(i)?;
+        let longer_word = lex_word(i).is_some_and(|(_, len)| len > t.len());
+        if punct(i) == Some(t) && !longer_word {
+            Ok((&i[t.len()..], &i[..t.len()]))
+        } else {
+            self.expect(i, Expected::Token(t))
+        }
+    }
+
+    fn keyword(&self, i: &'s str, kw: &'static str) -> PResult<'s, &'s str> {
+        let (i, _) = trivia(i)?;
+        match lex_word(i) {
+            Some((WordKind::Id, len)) if &i[..len] == kw => Ok((&i[len..], &i[..len])),
+            _ => self.expect(i, Expected::Token(kw)),
+        }
+    }
+
+    fn ident(&self, i: &'s str) -> PResult<'s, &'s str> {
+        let (i, _) = trivia(i)?;
+        match lex_word(i) {
+            Some((WordKind::Id, len)) if !KEYWORDS.contains(&&i[..len]) => {
+                Ok((&i[len..], &i[..len]))
+            }
+            _ => self.expect(i, Expected::Identifier),
+        }
+    }
+
+    fn expr(&self, i: &'s str) -> PResult<'s, Expr> {
+        self.expr_function(i)
+    }
+
+    fn expr_function(&self, i: &'s str) -> PResult<'s, Expr> {
+        let (i, _) = trivia(i)?;
+        let pos = self.pos(i);
+
+        // `x: body` and `x @ { ... }: body`
+        if let Some((rest, arg)) = attempt(self.ident(i))? {
+            if let Some((rest, _)) = attempt(self.sym(rest, ":"))? {
+                let (rest, body) = self.required_expr(rest)?;
+                return self.lambda(rest, pos, Some(arg), None, body);
+            }
+            if let Some((rest, _)) = attempt(self.sym(rest, "@"))? {
+                let (rest, formals) = self.formals(rest)?;
+                let (rest, _) = self.sym(rest, ":")?;
+                let (rest, body) = self.required_expr(rest)?;
+                return self.lambda(rest, pos, Some(arg), Some(formals), body);
+            }
+        }
+
+        // `{ ... }: body` and `{ ... } @ x: body`
+        if let Some((rest, formals)) = attempt(self.formals(i))? {
+            if let Some((rest, _)) = attempt(self.sym(rest, "@"))? {
+                let (rest, arg) = self.ident(rest)?;
+                let (rest, _) = self.sym(rest, ":")?;
+                let (rest, body) = self.required_expr(rest)?;
+                return self.lambda(rest, pos, Some(arg), Some(formals), body);
+            }
+            if let Some((rest, _)) = attempt(self.sym(rest, ":"))? {
+                let (rest, body) = self.required_expr(rest)?;
+                return self.lambda(rest, pos, None, Some(formals), body);
+            }
+        }
+
+        if let Some((rest, _)) = attempt(self.keyword(i, "assert"))? {
+            let (start, _) = trivia(rest)?;
+            let (rest, cond) = self.required_expr(rest)?;
+            let cond_source = start[..self.offset(rest) - self.offset(start)].to_owned();
+            let (rest, _) = self.sym(rest, ";")?;
+            let (rest, body) = self.required_expr(rest)?;
+            return Ok((
+                rest,
+                Expr::Assert(ExprAssert {
+                    pos,
+                    cond: Box::new(cond),
+                    cond_source,
+                    body: Box::new(body),
+                }),
+            ));
+        }
+
+        if let Some((rest, _)) = attempt(self.keyword(i, "with"))? {
+            let (rest, attrs) = self.required_expr(rest)?;
+            let (rest, _) = self.sym(rest, ";")?;
+            let (rest, body) = self.required_expr(rest)?;
+            return Ok((
+                rest,
+                Expr::With(ExprWith {
+                    pos,
+                    attrs: Box::new(attrs),
+                    body: Box::new(body),
+                    prev_with: Level(0),
+                }),
+            ));
+        }
+
+        // `let { ... }` is the old-style `let`, handled in `expr_simple`.
+        if let Some((rest, _)) = attempt(self.keyword(i, "let"))? {
+            if attempt(self.sym(rest, "{"))?.is_none() {
+                let (rest, mut attrs) = self.binds(rest)?;
+                if let Some(dynamic) = attrs.dynamic_attrs.first() {
+                    self.parse_error(
+                        rest,
+                        dynamic.pos.clone(),
+                        "dynamic attributes not allowed in let".to_owned(),
+                    )?;
+                    attrs.dynamic_attrs.clear();
+                }
+                attrs.recursive = true;
+                let (rest, _) = self.keyword(rest, "in")?;
+                let (rest, body) = self.required_expr(rest)?;
+                return Ok((
+                    rest,
+                    Expr::Let(ExprLet {
+                        attrs: Box::new(attrs),
+                        body: Box::new(body),
+                    }),
+                ));
+            }
+        }
+
+        self.expr_if(i)
+    }
+
+    fn lambda(
+        &self,
+        i: &'s str,
+        pos: Pos,
+        arg: Option<&str>,
+        formals: Option<Formals>,
+        body: Expr,
+    ) -> PResult<'s, Expr> {
+        let arg = arg.map(Symbol::new);
+        if let (Some(arg), Some(formals)) = (arg, &formals) {
+            if formals.has(arg) {
+                self.parse_error(
+                    i,
+                    pos.clone(),
+                    format!("duplicate formal function argument '{}'", arg),
+                )?;
+            }
+        }
+        Ok((
+            i,
+            Expr::Lambda(ExprLambda {
+                pos,
+                name: Cell::new(None),
+                arg,
+                formals,
+                body: Box::new(body),
+            }),
+        ))
+    }
+
+    /// `{ a, b ? default, ... }`
+    fn formals(&self, i: &'s str) -> PResult<'s, Formals> {
+        let (i, _) = trivia(i)?;
+        let pos = self.pos(i);
+        let (mut i, _) = self.sym(i, "{")?;
+        let mut formals = Formals {
+            formals: Vec::new(),
+            ellipsis: false,
+        };
+        loop {
+            if let Some((rest, _)) = attempt(self.sym(i, "..."))? {
+                formals.ellipsis = true;
+                i = rest;
+                break;
+            }
+            match attempt(self.ident(i))? {
+                Some((rest, name)) => {
+                    let name = Symbol::new(name);
+                    let (rest, def) = match attempt(self.sym(rest, "?"))? {
+                        Some((rest, _)) => {
+                            let (rest, def) = self.expr(rest)?;
+                            (rest, Some(Box::new(def)))
+                        }
+                        None => (rest, None),
+                    };
+                    if formals.has(name) {
+                        self.parse_error(
+                            rest,
+                            pos.clone(),
+                            format!("duplicate formal function argument '{}'", name),
+                        )?;
+                    } else {
+                        formals.formals.push(Formal { name, def });
+                    }
+                    match attempt(self.sym(rest, ","))? {
+                        Some((rest, _)) => i = rest,
+                        None => {
+                            i = rest;
+                            break;
+                        }
+                    }
+                }
+                None => break,
+            }
+        }
+        let (i, _) = self.sym(i, "}")?;
+        Ok((i, formals))
+    }
+
+    fn expr_if(&self, i: &'s str) -> PResult<'s, Expr> {
+        match attempt(self.keyword(i, "if"))? {
+            Some((i, _)) => {
+                let (i, _) = trivia(i)?;
+                let pos = self.pos(i);
+                let (i, cond) = self.required_expr(i)?;
+                let (i, _) = self.keyword(i, "then")?;
+                let (i, then) = self.required_expr(i)?;
+                let (i, _) = self.keyword(i, "else")?;
+                let (i, else_) = self.required_expr(i)?;
+                Ok((
+                    i,
+                    Expr::If(ExprIf {
+                        pos,
+                        cond: Box::new(cond),
+                        then: Box::new(then),
+                        else_: Box::new(else_),
+                    }),
+                ))
+            }
+            None => self.expr_op(i, 0),
+        }
+    }
+
+    /// Find the next binary operator binding at least as tightly as
+    /// `min_prec`.
+    fn binary_op(
+        &self,
+        i: &'s str,
+        min_prec: u8,
+    ) -> Result<Option<(&'s str, &'static str, u8, Assoc)>, PError<'s>> {
+        for &(op, prec, assoc) in BINARY_OPS {
+            if prec < min_prec {
+                continue;
+            }
+            if let Some((rest, _)) = attempt(self.sym(i, op))? {
+                return Ok(Some((rest, op, prec, assoc)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Operator expressions, parsed by precedence climbing.
+    fn expr_op(&self, i: &'s str, min_prec: u8) -> PResult<'s, Expr> {
+        let (i, _) = trivia(i)?;
+        let pos = self.pos(i);
+
+        let (mut i, mut lhs) = if let Some((rest, _)) = attempt(self.sym(i, "!"))? {
+            let (rest, _) = trivia(rest)?;
+            let operand_pos = self.pos(rest);
+            let (rest, e) = self.expr_op(rest, NOT_PREC + 1)?;
+            (rest, not(operand_pos, e))
+        } else if let Some((rest, _)) = attempt(self.sym(i, "-"))? {
+            let (rest, e) = self.expr_op(rest, NEGATE_PREC)?;
+            (rest, call_builtin("__sub", pos.clone(), Expr::Int(0), e))
+        } else {
+            self.expr_app(i)?
+        };
+
+        let mut last_nonassoc = None;
+        while let Some((rest, op, prec, assoc)) = self.binary_op(i, min_prec)? {
+            // `a == b == c` is a syntax error.
+            if last_nonassoc == Some(prec) {
+                break;
+            }
+            if assoc == Assoc::None {
+                last_nonassoc = Some(prec);
+            }
+
+            if op == "?" {
+                let (rest, attr_path) = self.attrpath(rest)?;
+                lhs = Expr::OpHasAttr(ExprOpHasAttr {
+                    expr: Box::new(lhs),
+                    attr_path,
+                });
+                i = rest;
+                continue;
+            }
+
+            let next_prec = if assoc == Assoc::Right {
+                prec
+            } else {
+                prec + 1
+            };
+            let (rest, rhs) = match self.expr_op(rest, next_prec) {
+                Err(nom::Err::Error(_)) if self.recovering => (rest, self.missing(rest)),
+                result => result?,
+            };
+            i = rest;
+            lhs = binary_op_expr(op, pos.clone(), lhs, rhs);
+        }
+
+        Ok((i, lhs))
+    }
+
+    /// Function application, `f x y`.
+    fn expr_app(&self, i: &'s str) -> PResult<'s, Expr> {
+        let (i, _) = trivia(i)?;
+        let pos = self.pos(i);
+        let (mut i, mut fun) = self.expr_select(i)?;
+        while let Some((rest, arg)) = attempt(self.expr_select(i))? {
+            fun = Expr::BinOp(BinOp::new(OpKind::App, pos.clone(), fun, arg));
+            i = rest;
+        }
+        Ok((i, fun))
+    }
+
+    /// `e.a.b`, `e.a.b or default`, and the legacy `e or` application.
+    fn expr_select(&self, i: &'s str) -> PResult<'s, Expr> {
+        let (i, _) = trivia(i)?;
+        let pos = self.pos(i);
+        let (i, expr) = self.expr_simple(i)?;
+
+        if let Some((rest, _)) = attempt(self.sym(i, "."))? {
+            let (rest, attr_path) = self.attrpath(rest)?;
+            let (rest, def) = match attempt(self.keyword(rest, "or"))? {
+                Some((rest, _)) => {
+                    let (rest, def) = self.expr_select(rest)?;
+                    (rest, Some(Box::new(def)))
+                }
+                None => (rest, None),
+            };
+            return Ok((
+                rest,
+                Expr::Select(ExprSelect {
+                    pos,
+                    expr: Box::new(expr),
+                    def,
+                    attr_path,
+                }),
+            ));
+        }
+
+        // Backwards compatibility: `or` used to be a plain variable, so
+        // `f or` applies `f` to it.
+        let (or_i, _) = trivia(i)?;
+        if let Some((rest, _)) = attempt(self.keyword(i, "or"))? {
+            let or = var(self.pos(or_i), "or");
+            return Ok((rest, Expr::BinOp(BinOp::new(OpKind::App, pos, expr, or))));
+        }
+
+        Ok((i, expr))
+    }
+
+    fn expr_simple(&self, i: &'s str) -> PResult<'s, Expr> {
+        let (i, _) = trivia(i)?;
+        let pos = self.pos(i);
+
+        match lex_word(i) {
+            Some((WordKind::Id, len)) if !KEYWORDS.contains(&&i[..len]) => {
+                let name = &i[..len];
+                let expr = if name == "__curPos" {
+                    Expr::Pos(pos)
+                } else {
+                    var(pos, name)
+                };
+                return Ok((&i[len..], expr));
+            }
+            Some((WordKind::Int, len)) => {
+                let n = i[..len].parse().or_else(|_| {
+                    self.parse_error(i, pos, format!("invalid integer '{}'", &i[..len]))
+                        .map(|()| 0)
+                })?;
+                return Ok((&i[len..], Expr::Int(n)));
+            }
+            Some((WordKind::Float, len)) => {
+                let n = i[..len].parse::<f64>().or_else(|_| {
+                    self.parse_error(i, pos, format!("invalid float '{}'", &i[..len]))
+                        .map(|()| 0.0)
+                })?;
+                return Ok((&i[len..], Expr::Float(NixFloat::from(n))));
+            }
+            Some((WordKind::Path, len)) | Some((WordKind::HPath, len)) => {
+                return self.path(&i[len..], &i[..len], pos);
+            }
+            // Only the leading segment; the antiquotation is handled by
+            // `path`.
+            Some((WordKind::PathStart, len)) | Some((WordKind::HPathStart, len)) => {
+                return self.path(&i[len - 2..], &i[..len - 2], pos);
+            }
+            // `<nixpkgs/lib>` is `__findFile __nixPath "nixpkgs/lib"`.
+            Some((WordKind::SPath, len)) => {
+                let path = Expr::String(i[1..len - 1].to_owned());
+                let nix_path = var(pos.clone(), "__nixPath");
+                return Ok((&i[len..], call_builtin("__findFile", pos, nix_path, path)));
+            }
+            Some((WordKind::Uri, len)) => {
+                return Ok((&i[len..], Expr::String(i[..len].to_owned())));
+            }
+            _ => {}
+        }
+
+        if let Some((rest, _)) = attempt(self.sym(i, "\""))? {
+            return self.string(rest, pos);
+        }
+
+        if let Some((rest, _)) = attempt(self.sym(i, "''"))? {
+            return self.ind_string(rest, pos);
+        }
+
+        if let Some((rest, _)) = attempt(self.sym(i, "("))? {
+            let (rest, expr) = self.required_expr(rest)?;
+            let (rest, _) = self.close(rest, ")")?;
+            return Ok((rest, expr));
+        }
+
+        // `let { ...; body = ...; }` is an old-style `let`, equivalent to
+        // `rec { ... }.body`.
+        if let Some((rest, _)) = attempt(self.keyword(i, "let"))? {
+            let (rest, _) = self.sym(rest, "{")?;
+            let (rest, mut attrs) = self.binds(rest)?;
+            let (rest, _) = self.close(rest, "}")?;
+            attrs.recursive = true;
+            return Ok((
+                rest,
+                Expr::Select(ExprSelect {
+                    pos: Pos::Undefined,
+                    expr: Box::new(Expr::Attrs(attrs)),
+                    def: None,
+                    attr_path: vec![AttrName::Static(Symbol::new("body"))],
+                }),
+            ));
+        }
+
+        if let Some((rest, _)) = attempt(self.keyword(i, "rec"))? {
+            let (rest, _) = self.sym(rest, "{")?;
+            let (rest, mut attrs) = self.binds(rest)?;
+            let (rest, _) = self.close(rest, "}")?;
+            attrs.recursive = true;
+            return Ok((rest, Expr::Attrs(attrs)));
+        }
+
+        if let Some((rest, _)) = attempt(self.sym(i, "{"))? {
+            let (rest, attrs) = self.binds(rest)?;
+            let (rest, _) = self.close(rest, "}")?;
+            return Ok((rest, Expr::Attrs(attrs)));
+        }
+
+        if let Some((mut rest, _)) = attempt(self.sym(i, "["))? {
+            let mut elems = Vec::new();
+            while let Some((next, elem)) = attempt(self.expr_select(rest))? {
+                elems.push(elem);
+                rest = next;
+            }
+            let (rest, _) = self.close(rest, "]")?;
+            return Ok((rest, Expr::List(elems)));
+        }
+
+        self.expect(i, Expected::Expression)
+    }
+
+    /// A path literal starting with `first`, which may continue with
+    /// antiquotations like `./foo/${bar}.nix`.
+    fn path(&self, mut i: &'s str, first: &'s str, pos: Pos) -> PResult<'s, Expr> {
+        let path = match resolve_path(first, &self.base_path) {
+            Ok(path) => path,
+            Err(msg) => {
+                self.parse_error(i, pos.clone(), msg)?;
+                String::new()
+            }
+        };
+
+        let mut parts = Vec::new();
+        let mut trailing_slash = first.ends_with('/');
+        loop {
+            if i.starts_with("${") {
+                let (rest, expr) = self.required_expr(&i[2..])?;
+                let (rest, _) = self.sym(rest, "}")?;
+                parts.push(expr);
+                trailing_slash = false;
+                i = rest;
+                continue;
+            }
+            let len = lex_path(i)
+                .max(lex_path_seg(i))
+                .max(count(i.as_bytes(), 0, is_path_char));
+            if len == 0 {
+                break;
+            }
+            parts.push(Expr::String(i[..len].to_owned()));
+            trailing_slash = i[..len].ends_with('/');
+            i = &i[len..];
+        }
+
+        if trailing_slash {
+            self.parse_error(i, pos.clone(), "path has a trailing slash".to_owned())?;
+        }
+        if parts.is_empty() {
+            return Ok((i, Expr::Path(PathBuf::from(path))));
+        }
+        parts.insert(0, Expr::Path(PathBuf::from(path)));
+        Ok((
+            i,
+            Expr::ConcatStrings(ExprConcatStrings {
+                pos,
+                force_string: false,
+                exprs: parts,
+            }),
+        ))
+    }
+
+    /// The rest of a double-quoted string, after the opening `"`.
+    fn string(&self, mut i: &'s str, pos: Pos) -> PResult<'s, Expr> {
+        let mut parts = Vec::new();
+        let mut interpolated = false;
+        let mut s = String::new();
+        loop {
+            i = &i[unescape_string(i, &mut s)..];
+            if let Some(rest) = i.strip_prefix('"') {
+                i = rest;
+                break;
+            } else if let Some(rest) = i.strip_prefix("${") {
+                if !s.is_empty() {
+                    parts.push(Expr::String(std::mem::take(&mut s)));
+                }
+                let (rest, expr) = self.required_expr(rest)?;
+                let (rest, _) = self.sym(rest, "}")?;
+                parts.push(expr);
+                interpolated = true;
+                i = rest;
+            } else {
+                // The end of the file, possibly after a backslash.
+                return self.expect(i.strip_prefix('\\').unwrap_or(i), Expected::Token("\""));
+            }
+        }
+
+        if !interpolated {
+            return Ok((i, Expr::String(s)));
+        }
+        if !s.is_empty() {
+            parts.push(Expr::String(s));
+        }
+        Ok((
+            i,
+            Expr::ConcatStrings(ExprConcatStrings {
+                pos,
+                force_string: true,
+                exprs: parts,
+            }),
+        ))
+    }
+
+    /// The rest of an indented string, after the opening `''`.
+    ///
+    /// The pieces are split up the same way upstream's lexer splits them,
+    /// since that affects which trailing line `strip_indentation` drops.
+    fn ind_string(&self, i: &'s str, pos: Pos) -> PResult<'s, Expr> {
+        let mut i = skip_ind_string_start(i);
+        let mut parts = Vec::new();
+        loop {
+            i = &i[ind_string_parts(i, &mut parts)..];
+            if let Some(rest) = i.strip_prefix("${") {
+                let (rest, expr) = self.required_expr(rest)?;
+                let (rest, _) = self.sym(rest, "}")?;
+                parts.push(IndStringPart::Antiquote(expr));
+                i = rest;
+            } else if let Some(rest) = i.strip_prefix("''") {
+                i = rest;
+                break;
+            } else {
+                return self.expect(i, Expected::Token("''"));
+            }
+        }
+
+        Ok((i, strip_indentation(pos, parts)))
+    }
+
+    /// The body of an attribute set or `let`: a sequence of `path = expr;`
+    /// and `inherit` bindings.
+    ///
+    /// When recovering, broken bindings are skipped up to the next `;`, and
+    /// broken values are replaced with error nodes.
+    fn binds(&self, mut i: &'s str) -> PResult<'s, ExprAttrs> {
+        let mut attrs = ExprAttrs::default();
+        loop {
+            match self.bind(i, &mut attrs) {
+                Ok((rest, true)) => i = rest,
+                Ok((rest, false)) => return Ok((rest, attrs)),
+                Err(nom::Err::Error(_)) if self.recovering => {
+                    i = self.recover(i, &[";"], &["}", "in"]);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// A single binding, returning whether there was one.
+    fn bind(&self, i: &'s str, attrs: &mut ExprAttrs) -> PResult<'s, bool> {
+        if let Some((rest, _)) = attempt(self.keyword(i, "inherit"))? {
+            let (rest, _) = self.inherit(rest, attrs)?;
+            return Ok((rest, true));
+        }
+
+        let (rest, _) = trivia(i)?;
+        let pos = self.pos(rest);
+        match attempt(self.attrpath(rest))? {
+            Some((rest, attr_path)) => {
+                let (rest, _) = self.sym(rest, "=")?;
+                let (rest, expr) = self.required_expr(rest)?;
+                // A binding missing its `;` is kept, and whatever follows
+                // it up to the next binding is skipped.
+                let rest = match self.sym(rest, ";") {
+                    Err(nom::Err::Error(_)) if self.recovering => {
+                        self.recover(rest, &[";"], &["}", "in"])
+                    }
+                    result => result?.0,
+                };
+                self.add_attr(rest, attrs, attr_path, expr, pos)?;
+                Ok((rest, true))
+            }
+            // Anything but the end of the bindings is garbage to skip.
+            None if self.recovering => match self.peek(rest) {
+                None | Some(("}", _)) | Some(("in", _)) => Ok((i, false)),
+                Some(_) => Err(nom::Err::Error((rest, ErrorKind::Verify))),
+            },
+            None => Ok((i, false)),
+        }
+    }
+
+    /// The rest of an `inherit` or `inherit (expr)` binding, after the
+    /// keyword.
+    fn inherit(&self, i: &'s str, attrs: &mut ExprAttrs) -> PResult<'s, ()> {
+        let (mut i, from) = match attempt(self.sym(i, "("))? {
+            Some((rest, _)) => {
+                let (rest, from) = self.required_expr(rest)?;
+                let (rest, _) = self.sym(rest, ")")?;
+                (rest, Some(from))
+            }
+            None => (i, None),
+        };
+
+        loop {
+            if let Some((rest, _)) = attempt(self.sym(i, ";"))? {
+                return Ok((rest, ()));
+            }
+            let (rest, _) = trivia(i)?;
+            let pos = self.pos(rest);
+            let (rest, name) = match self.attr(rest)? {
+                (rest, AttrName::Static(name)) => (rest, name),
+                (rest, AttrName::Dynamic(_)) => {
+                    self.parse_error(
+                        rest,
+                        pos,
+                        "dynamic attributes not allowed in inherit".to_owned(),
+                    )?;
+                    i = rest;
+                    continue;
+                }
+            };
+            if let Err(err) = attrs.add_inherited(name, from.as_ref(), pos) {
+                self.error(rest, err)?;
+            }
+            i = rest;
+        }
+    }
+
+    /// A dot-separated attribute path, like `a."b".${c}`.
+    fn attrpath(&self, i: &'s str) -> PResult<'s, AttrPath> {
+        let (mut i, first) = self.attr(i)?;
+        let mut path = vec![first];
+        while let Some((rest, _)) = attempt(self.sym(i, "."))? {
+            let (rest, attr) = self.attr(rest)?;
+            path.push(attr);
+            i = rest;
+        }
+        Ok((i, path))
+    }
+
+    /// A single attribute name: an identifier (including `or`), a string,
+    /// or `${expr}`.
+    fn attr(&self, i: &'s str) -> PResult<'s, AttrName> {
+        let (i, _) = trivia(i)?;
+        let pos = self.pos(i);
+        if let Some((WordKind::Id, len)) = lex_word(i) {
+            let name = &i[..len];
+            if name == "or" || !KEYWORDS.contains(&name) {
+                return Ok((&i[len..], AttrName::Static(Symbol::new(name))));
+            }
+        }
+        if let Some((rest, _)) = attempt(self.sym(i, "\""))? {
+            let (rest, name) = self.string(rest, pos)?;
+            return Ok((
+                rest,
+                match name {
+                    Expr::String(name) => AttrName::Static(Symbol::new(&name)),
+                    name => AttrName::Dynamic(Box::new(name)),
+                },
+            ));
+        }
+        if let Some((rest, _)) = attempt(self.sym(i, "${"))? {
+            let (rest, name) = self.required_expr(rest)?;
+            let (rest, _) = self.sym(rest, "}")?;
+            return Ok((rest, AttrName::Dynamic(Box::new(name))));
+        }
+        self.expect(i, Expected::Identifier)
+    }
+
+    /// Add `path = expr` to `attrs`, merging nested attribute sets for
+    /// paths like `a.b.c`. When recovering, the first definition wins.
+    fn add_attr(
+        &self,
+        i: &'s str,
+        attrs: &mut ExprAttrs,
+        path: AttrPath,
+        expr: Expr,
+        pos: Pos,
+    ) -> PResult<'s, ()> {
+        if let Err(err) = attrs.add_attr(path, expr, pos) {
+            self.error(i, err)?;
+        }
+        Ok((i, ()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    /// Parse `source` and print it like `nix-instantiate --parse`, or print
+    /// the error.
+    fn parse_print(source: &str) -> String {
+        match parse(source, "test.nix", Path::new("/base/dir")) {
+            Ok(expr) => expr.to_string(),
+            Err(err) => format!("error: {}", err),
+        }
+    }
+
+    #[test]
+    fn operators() {
+        assert_eq!(
+            parse_print("1 + 2 * 3 - 4 / 5"),
+            "((__sub (1 + ((__mul 2) 3))) ((__div 4) 5))"
+        );
+        assert_eq!(parse_print("-x"), "((__sub 0) x)");
+        assert_eq!(
+            parse_print("!a && b || c -> d"),
+            "((((! a) && b) || c) -> d)"
+        );
+        assert_eq!(parse_print("a < b"), "((__lessThan a) b)");
+        assert_eq!(parse_print("a >= b"), "(! ((__lessThan a) b))");
+        assert_eq!(parse_print("a // b // c"), "(a // (b // c))");
+        assert_eq!(parse_print("a ++ b ++ c"), "(a ++ (b ++ c))");
+        assert_eq!(parse_print("a ? b.c"), "((a) ? b.c)");
+    }
+
+    #[test]
+    fn non_associative_operators() {
+        assert_eq!(
+            parse_print("a == b == c"),
+            "error: syntax error, expected '.', 'or', expression, end of file, ..., \
+             found '==', at test.nix:1:8"
+        );
+    }
+
+    #[test]
+    fn select_and_application() {
+        assert_eq!(parse_print("a.b.c or d"), "(a).b.c or (d)");
+        assert_eq!(parse_print("a.${b}.\"c\""), "(a).\"${b}\".c");
+        assert_eq!(parse_print("f x y"), "((f x) y)");
+        assert_eq!(parse_print("f or"), "(f or)");
+    }
+
+    #[test]
+    fn lambdas() {
+        assert_eq!(parse_print("x: y: x"), "(x: (y: x))");
+        assert_eq!(
+            parse_print("{ a, b ? 1, ... }@args: a"),
+            "({ a, b ? 1, ... } @ args: a)"
+        );
+        assert_eq!(parse_print("args@{ a }: a"), "({ a } @ args: a)");
+        assert_eq!(parse_print("{}: 1"), "({  }: 1)");
+        assert_eq!(parse_print("x: y@{}: z"), "(x: ({  } @ y: z))");
+        assert_eq!(
+            parse_print("{ a, a }: a"),
+            "error: duplicate formal function argument 'a', at test.nix:1:1"
+        );
+    }
+
+    #[test]
+    fn bindings() {
+        assert_eq!(
+            parse_print("let a = 1; inherit b; inherit (c) d e; in a"),
+            "(let a = 1; inherit b; d = (c).d; e = (c).e; in a)"
+        );
+        assert_eq!(parse_print("let { body = 1; }"), "(rec { body = 1; }).body");
+        assert_eq!(
+            parse_print("{ a = 1; a = 2; }"),
+            "error: attribute 'a' at test.nix:1:10 already defined at test.nix:1:3"
+        );
+        assert_eq!(
+            parse_print("{ inherit ${a}; }"),
+            "error: dynamic attributes not allowed in inherit, at test.nix:1:11"
+        );
+    }
+
+    #[test]
+    fn nested_attr_paths() {
+        assert_eq!(
+            parse_print("{ a.b = 1; a.c = 2; }"),
+            "{ a = { b = 1; c = 2; }; }"
+        );
+        assert_eq!(
+            parse_print("{ a = { b = 1; }; a.c = 2; }"),
+            "{ a = { b = 1; c = 2; }; }"
+        );
+        assert_eq!(
+            parse_print("{ a.b.c = 1; a.b.d = 2; a.e = 3; }"),
+            "{ a = { b = { c = 1; d = 2; }; e = 3; }; }"
+        );
+        assert_eq!(
+            parse_print("let a.b = 1; a.c = 2; in a"),
+            "(let a = { b = 1; c = 2; }; in a)"
+        );
+        assert_eq!(
+            parse_print("{ a.${b} = 1; a.c = 2; }"),
+            r#"{ a = { c = 2; "${b}" = 1; }; }"#
+        );
+        assert_eq!(
+            parse_print("{ a = rec { b = 1; }; a.c = b; }"),
+            "{ a = rec { b = 1; c = b; }; }"
+        );
+    }
+
+    #[test]
+    fn nested_attr_path_conflicts() {
+        assert_eq!(
+            parse_print("{ a.b = 1; a = { c = 2; }; }"),
+            "error: attribute 'a' at test.nix:1:12 already defined at test.nix:1:3"
+        );
+        assert_eq!(
+            parse_print("{ a.b = 1; a.b = 2; }"),
+            "error: attribute 'a.b' at test.nix:1:12 already defined at test.nix:1:3"
+        );
+        assert_eq!(
+            parse_print("{ a = 1; a.b = 2; }"),
+            "error: attribute 'a.b' at test.nix:1:10 already defined at test.nix:1:3"
+        );
+        assert_eq!(
+            parse_print("{ inherit a; a.b = 1; }"),
+            "error: attribute 'a.b' at test.nix:1:14 already defined at test.nix:1:11"
+        );
+    }
+
+    #[test]
+    fn keywords() {
+        assert_eq!(parse_print("with a; b"), "(with a; b)");
+        assert_eq!(parse_print("assert a == b; c"), "assert (a == b); c");
+        assert_eq!(parse_print("if a then b else c"), "(if a then b else c)");
+        assert_eq!(parse_print("__curPos"), "__curPos");
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(
+            parse_print("[ 1 2.5 \"x\" (f x) ]"),
+            "[ (1) (2.5) (\"x\") ((f x)) ]"
+        );
+        assert_eq!(parse_print("1.5e10"), "1.5e+10");
+        assert_eq!(parse_print(".5"), "0.5");
+        assert_eq!(
+            parse_print("9999999999999999999999"),
+            "error: invalid integer '9999999999999999999999', at test.nix:1:1"
+        );
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(
+            parse_print(r#""a\nb\t\"c\\ $${x} ${y} z$$""#),
+            r#"("a\nb\t\"c\\ \$\${x} " + y + " z\$\$")"#
+        );
+        assert_eq!(parse_print(r#""\r\n x \r""#), r#""\r\n x \r""#);
+        assert_eq!(parse_print(r#""abc\$""#), r#""abc\$""#);
+        assert_eq!(
+            parse_print(r#""unterminated"#),
+            "error: syntax error, expected '\"', found end of file, at test.nix:1:14"
+        );
+    }
+
+    #[test]
+    fn indented_strings() {
+        assert_eq!(
+            parse_print(indoc!(
+                "
+                ''
+                  a
+                    b
+                ''"
+            )),
+            r#""a\n  b\n""#
+        );
+        assert_eq!(parse_print("''  x\n   y\n  ''"), r#""x\n y\n""#);
+        // Tabs aren't indentation.
+        assert_eq!(parse_print("''\n\t  x\n  ''"), r#""\t  x\n""#);
+        assert_eq!(
+            parse_print("''\n  foo\n  ${bar}\n  ''"),
+            r#"("foo\n" + bar + "\n")"#
+        );
+        assert_eq!(
+            parse_print("''a$b'c $'"),
+            "error: syntax error, expected '''', found end of file, at test.nix:1:11"
+        );
+    }
+
+    #[test]
+    fn indented_string_escapes() {
+        assert_eq!(
+            parse_print("''  \n  a ''$ ''' ''\\n ${x}\n  ''"),
+            r#"("a " + "\$" + " " + "''" + " " + "\n" + " " + x + "\n")"#
+        );
+    }
+
+    #[test]
+    fn interpolation_forces_strings() {
+        for source in &[r#""${a}""#, "''${a}''"] {
+            match parse(source, "test.nix", Path::new("/")).unwrap() {
+                Expr::ConcatStrings(concat) => {
+                    assert!(concat.force_string);
+                    assert_eq!(concat.exprs.len(), 1);
+                }
+                expr => panic!("not a string: {}", expr),
+            }
+        }
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(parse_print("./a/b/../c"), "/base/dir/a/c");
+        assert_eq!(parse_print("a/b/c"), "/base/dir/a/b/c");
+        assert_eq!(parse_print("/a/./b/../c"), "/a/c");
+        assert_eq!(
+            parse_print("./a/${x}.nix"),
+            r#"(/base/dir/a/ + x + ".nix")"#
+        );
+        assert_eq!(
+            parse_print("./a/"),
+            "error: path has a trailing slash, at test.nix:1:1"
+        );
+    }
+
+    #[test]
+    fn home_paths() {
+        if let Ok(home) = std::env::var("HOME") {
+            assert_eq!(parse_print("~/foo"), canon_path(&format!("{}/foo", home)));
+        }
+    }
+
+    #[test]
+    fn search_paths() {
+        assert_eq!(
+            parse_print("<nixpkgs/lib>"),
+            r#"((__findFile __nixPath) "nixpkgs/lib")"#
+        );
+        assert_eq!(parse_print("f <b>"), r#"(f ((__findFile __nixPath) "b"))"#);
+    }
+
+    #[test]
+    fn uris() {
+        assert_eq!(
+            parse_print("http://example.com/x?y=z"),
+            r#""http://example.com/x?y=z""#
+        );
+        assert_eq!(parse_print("[ a:b ]"), r#"[ ("a:b") ]"#);
+    }
+
+    /// Parse `source` with error recovery, printing the partial expression
+    /// and the errors.
+    fn parse_print_recovering(source: &str) -> (Option<String>, Vec<String>) {
+        let (expr, errors) = parse_recovering(source, "test.nix", Path::new("/base/dir"));
+        (
+            expr.map(|expr| expr.to_string()),
+            errors.iter().map(ToString::to_string).collect(),
+        )
+    }
+
+    #[test]
+    fn recover_missing_operand() {
+        assert_eq!(
+            parse_print_recovering("(1 + )"),
+            (
+                Some("(1 + «error»)".to_owned()),
+                vec!["syntax error, expected expression, found ')', at test.nix:1:6".to_owned()]
+            )
+        );
+    }
+
+    #[test]
+    fn recover_every_binding() {
+        assert_eq!(
+            parse_print_recovering("{ a = ; b = 2; c = 3 }"),
+            (
+                Some("{ a = «error»; b = 2; c = 3; }".to_owned()),
+                vec![
+                    "syntax error, expected expression, found ';', at test.nix:1:7".to_owned(),
+                    "syntax error, expected '.', 'or', expression, ';', ..., found '}', \
+                     at test.nix:1:22"
+                        .to_owned(),
+                ]
+            )
+        );
+        assert_eq!(
+            parse_print_recovering("{ a = 1; a = 2; b = }"),
+            (
+                Some("{ a = 1; b = «error»; }".to_owned()),
+                vec![
+                    "attribute 'a' at test.nix:1:10 already defined at test.nix:1:3".to_owned(),
+                    "syntax error, expected expression, found '}', at test.nix:1:21".to_owned(),
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn recover_missing_semicolon() {
+        assert_eq!(
+            parse_print_recovering("let a = 1 b = 2; in a"),
+            (
+                Some("(let a = (1 b); in a)".to_owned()),
+                vec![
+                    "syntax error, expected '.', 'or', expression, ';', ..., found '=', \
+                     at test.nix:1:13"
+                        .to_owned()
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn recover_sub_expressions() {
+        assert_eq!(
+            parse_print_recovering("f (x: ) y"),
+            (
+                Some("((f (x: «error»)) y)".to_owned()),
+                vec!["syntax error, expected expression, found ')', at test.nix:1:7".to_owned()]
+            )
+        );
+        assert_eq!(
+            parse_print_recovering("if a then else c"),
+            (
+                Some("(if a then «error» else c)".to_owned()),
+                vec![
+                    "syntax error, expected expression, found 'else', at test.nix:1:11".to_owned()
+                ]
+            )
+        );
+        assert_eq!(
+            parse_print_recovering("let a = ; in a"),
+            (
+                Some("(let a = «error»; in a)".to_owned()),
+                vec!["syntax error, expected expression, found ';', at test.nix:1:9".to_owned()]
+            )
+        );
+        assert_eq!(
+            parse_print_recovering(r#"{ a = "${}"; b = 1; }"#),
+            (
+                Some("{ a = («error»); b = 1; }".to_owned()),
+                vec!["syntax error, expected expression, found '}', at test.nix:1:10".to_owned()]
+            )
+        );
+    }
+
+    #[test]
+    fn recover_several_errors() {
+        let (expr, errors) =
+            parse_print_recovering("{ a = f (x: ); b = if then 1 else 2; c = 3; }");
+        assert_eq!(
+            expr.as_deref(),
+            Some("{ a = (f (x: «error»)); b = (if «error» then 1 else 2); c = 3; }")
+        );
+        assert_eq!(
+            errors,
+            vec![
+                "syntax error, expected expression, found ')', at test.nix:1:13".to_owned(),
+                "syntax error, expected expression, found 'then', at test.nix:1:23".to_owned(),
+            ]
+        );
+    }
+
+    #[test]
+    fn recover_once_per_position() {
+        assert_eq!(
+            parse_print_recovering("[ 1 ) 2 ]"),
+            (
+                Some("[ (1) ]".to_owned()),
+                vec![
+                    "syntax error, expected '.' or 'or' or expression or ']', found ')', \
+                     at test.nix:1:5"
+                        .to_owned()
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn recover_nothing() {
+        assert_eq!(
+            parse_print_recovering(")"),
+            (
+                None,
+                vec!["syntax error, expected expression, found ')', at test.nix:1:1".to_owned()]
+            )
+        );
+    }
+
+    #[test]
+    fn recover_without_errors() {
+        assert_eq!(
+            parse_print_recovering("a: a"),
+            (Some("(a: a)".to_owned()), vec![])
+        );
+    }
+
+    #[test]
+    fn comments() {
+        assert_eq!(parse_print("# a\n1 /* b */ + /* c */ 2"), "(1 + 2)");
+    }
+
+    #[test]
+    fn positions() {
+        let expr = parse("\n  x", "test.nix", Path::new("/")).unwrap();
+        match expr {
+            Expr::Var(var) => assert_eq!(var.pos.to_string(), "test.nix:2:3"),
+            expr => panic!("not a variable: {}", expr),
+        }
+    }
+}
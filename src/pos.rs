@@ -1,33 +1,32 @@
-use std::borrow::{Borrow, ToOwned};
 use std::fmt;
 use std::fmt::{Display, Formatter};
+use std::rc::Rc;
 
-use crate::symbol_table::Symbol;
-
-#[derive(Debug, PartialEq)]
-pub struct KnownPos<'arena> {
-    pub file: Symbol<'arena>,
+#[derive(Clone, Debug, PartialEq)]
+pub struct KnownPos {
+    /// Shared by every position in the file.
+    pub file: Rc<str>,
     pub line: usize,
     pub column: usize,
 }
 
-impl Display for KnownPos<'_> {
+impl Display for KnownPos {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(f, "{}:{}:{}", self.file, self.line, self.column)
     }
 }
 
-impl<'arena> KnownPos<'arena> {
+impl KnownPos {
     pub fn to_owned(&self) -> OwnedKnownPos {
         OwnedKnownPos {
-            file: self.file.to_owned(),
+            file: self.file.to_string(),
             line: self.line,
             column: self.column,
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct OwnedKnownPos {
     pub file: String,
     pub line: usize,
@@ -40,13 +39,13 @@ impl Display for OwnedKnownPos {
     }
 }
 
-#[derive(Debug, PartialEq)]
-pub enum Pos<'arena> {
+#[derive(Clone, Debug, PartialEq)]
+pub enum Pos {
     Undefined,
-    Known(KnownPos<'arena>),
+    Known(KnownPos),
 }
 
-impl<'arena> Pos<'arena> {
+impl Pos {
     pub fn to_owned(&self) -> OwnedPos {
         match self {
             Pos::Undefined => OwnedPos::Undefined,
@@ -55,7 +54,7 @@ impl<'arena> Pos<'arena> {
     }
 }
 
-impl Display for Pos<'_> {
+impl Display for Pos {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
             Pos::Undefined => write!(f, "undefined position"),
@@ -64,7 +63,7 @@ impl Display for Pos<'_> {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum OwnedPos {
     Undefined,
     Known(OwnedKnownPos),
@@ -0,0 +1,162 @@
+use std::collections::BTreeMap;
+
+use crate::attr_set::Bindings;
+use crate::eval::EvalState;
+use crate::pos::Pos;
+use crate::value::{ContextElem, NixContext, NixString};
+use crate::{NixResult, Symbol, Value};
+
+pub(crate) fn prim_unsafe_discard_string_context<'arena>(
+    state: &EvalState<'arena>,
+    pos: &Pos,
+    args: &[Value<'arena>],
+) -> NixResult<Value<'arena>> {
+    let mut context = NixContext::new();
+    let s = state.coerce_to_string(args[0].clone(), &mut context, false, true, pos)?;
+    Ok(Value::String(NixString::new(s)))
+}
+
+pub(crate) fn prim_has_context<'arena>(
+    state: &EvalState<'arena>,
+    pos: &Pos,
+    args: &[Value<'arena>],
+) -> NixResult<Value<'arena>> {
+    let s = state.force_string(args[0].clone(), pos)?;
+    Ok(Value::Bool(!s.context.is_empty()))
+}
+
+/// Turn references to derivations and everything they depend on into plain
+/// references to the `.drv` files, so that depending on the string doesn't
+/// build anything.
+pub(crate) fn prim_unsafe_discard_output_dependency<'arena>(
+    state: &EvalState<'arena>,
+    pos: &Pos,
+    args: &[Value<'arena>],
+) -> NixResult<Value<'arena>> {
+    let mut context = NixContext::new();
+    let s = state.coerce_to_string(args[0].clone(), &mut context, false, true, pos)?;
+    let context = context
+        .into_iter()
+        .map(|elem| match elem {
+            ContextElem::AllOutputs(drv) => ContextElem::Opaque(drv),
+            elem => elem,
+        })
+        .collect();
+    Ok(Value::String(NixString { s, context }))
+}
+
+/// The context of a string as a set from store paths to what the string
+/// refers to of them, like
+/// `{ "/nix/store/...drv" = { allOutputs = true; outputs = [ "out" ]; }; }`.
+pub(crate) fn prim_get_context<'arena>(
+    state: &EvalState<'arena>,
+    pos: &Pos,
+    args: &[Value<'arena>],
+) -> NixResult<Value<'arena>> {
+    #[derive(Default)]
+    struct ContextInfo {
+        path: bool,
+        all_outputs: bool,
+        outputs: Vec<String>,
+    }
+
+    let s = state.force_string(args[0].clone(), pos)?;
+    let mut infos = BTreeMap::<_, ContextInfo>::new();
+    for elem in &s.context {
+        let info = infos.entry(elem.path()).or_default();
+        match elem {
+            ContextElem::Opaque(_) => info.path = true,
+            ContextElem::AllOutputs(_) => info.all_outputs = true,
+            ContextElem::Output { output, .. } => info.outputs.push(output.clone()),
+        }
+    }
+
+    let mut attrs = Bindings::new();
+    for (path, info) in infos {
+        let mut info_attrs = Bindings::new();
+        if info.path {
+            info_attrs.insert(Symbol::new("path"), Value::Bool(true), pos.clone());
+        }
+        if info.all_outputs {
+            info_attrs.insert(Symbol::new("allOutputs"), Value::Bool(true), pos.clone());
+        }
+        if !info.outputs.is_empty() {
+            let outputs = info
+                .outputs
+                .into_iter()
+                .map(|output| Value::String(NixString::new(output)))
+                .collect();
+            info_attrs.insert(state.sOutputs, Value::List(outputs), pos.clone());
+        }
+        attrs.insert(Symbol::new(path), Value::Attrs(info_attrs), pos.clone());
+    }
+    Ok(Value::Attrs(attrs))
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::eval::tests::{apply, eval};
+
+    /// A string referring to all outputs of a derivation, and its `dev`
+    /// output.
+    fn string_with_context() -> NixString {
+        let mut s = NixString::new("/nix/store/bbbb-foo-dev".to_owned());
+        s.context
+            .insert(ContextElem::decode("=/nix/store/aaaa-foo.drv"));
+        s.context
+            .insert(ContextElem::decode("!dev!/nix/store/aaaa-foo.drv"));
+        s
+    }
+
+    #[test]
+    fn get_context() {
+        assert_eq!(eval(r#"builtins.getContext "x""#), "{ }");
+        assert_eq!(
+            apply("builtins.getContext", string_with_context()),
+            r#"{ /nix/store/aaaa-foo.drv = { allOutputs = true; outputs = [ "dev" ]; }; }"#
+        );
+        assert_eq!(
+            apply(
+                "s: builtins.getContext (builtins.unsafeDiscardOutputDependency s)",
+                string_with_context()
+            ),
+            r#"{ /nix/store/aaaa-foo.drv = { outputs = [ "dev" ]; path = true; }; }"#
+        );
+    }
+
+    #[test]
+    fn has_context() {
+        assert_eq!(eval(r#"builtins.hasContext "x""#), "false");
+        assert_eq!(apply("builtins.hasContext", string_with_context()), "true");
+        assert_eq!(
+            apply(
+                "s: builtins.hasContext (builtins.unsafeDiscardStringContext s)",
+                string_with_context()
+            ),
+            "false"
+        );
+    }
+
+    #[test]
+    fn context_propagation() {
+        for source in &[
+            r#"s: builtins.hasContext "x${s}""#,
+            r#"s: builtins.hasContext (toString [ s ])"#,
+            "s: builtins.hasContext (builtins.substring 0 0 s)",
+            r#"s: builtins.hasContext (builtins.replaceStrings [ "x" ] [ "y" ] s)"#,
+            r#"s: builtins.hasContext (builtins.replaceStrings [ "x" ] [ s ] "x")"#,
+        ] {
+            assert_eq!(apply(source, string_with_context()), "true", "{}", source);
+        }
+        assert_eq!(
+            apply(
+                r#"s: builtins.hasContext (builtins.replaceStrings [ "x" ] [ s ] "y")"#,
+                string_with_context()
+            ),
+            "false"
+        );
+    }
+}
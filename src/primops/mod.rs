@@ -3,13 +3,30 @@ pub mod fetch_git;
 pub mod fetch_mercurial;
 pub mod from_toml;
 
-type PrimOpFun = ();
+use crate::attr_set::Bindings;
+use crate::eval::EvalState;
+use crate::pos::Pos;
+use crate::value::{NixContext, NixFloat, NixInt, NixString};
+use crate::{NixError, NixResult, Value};
 
-#[derive(Debug, PartialEq)]
+/// A built-in function. It is called with exactly `arity` arguments, which
+/// haven't been forced yet.
+pub type PrimOpFun =
+    for<'arena> fn(&EvalState<'arena>, &Pos, &[Value<'arena>]) -> NixResult<Value<'arena>>;
+
+#[derive(Clone, Debug)]
 pub struct PrimOp {
-    name: String,
-    arity: usize,
-    fun: PrimOpFun,
+    pub name: String,
+    pub arity: usize,
+    pub fun: PrimOpFun,
+}
+
+/// Primops are identified by name, since function pointers can't be
+/// compared reliably.
+impl PartialEq for PrimOp {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.arity == other.arity
+    }
 }
 
 pub struct RegisterPrimOp {
@@ -17,7 +34,444 @@ pub struct RegisterPrimOp {
 }
 
 impl RegisterPrimOp {
-    fn register(&mut self, prim_op: PrimOp) {
+    pub fn register(&mut self, prim_op: PrimOp) {
         self.prim_ops.push(prim_op)
     }
 }
+
+impl<'arena> EvalState<'arena> {
+    pub(crate) fn create_base_env(&mut self) {
+        self.add_constant("builtins", Value::Attrs(Default::default()));
+        self.add_constant("true", Value::Bool(true));
+        self.add_constant("false", Value::Bool(false));
+        self.add_constant("null", Value::Null);
+
+        self.add_primop("__sub", 2, prim_sub);
+        self.add_primop("__mul", 2, prim_mul);
+        self.add_primop("__div", 2, prim_div);
+        self.add_primop("__lessThan", 2, prim_less_than);
+
+        self.add_primop("toString", 1, prim_to_string);
+        self.add_primop("__substring", 3, prim_substring);
+        self.add_primop("__replaceStrings", 3, prim_replace_strings);
+
+        self.add_primop(
+            "__unsafeDiscardStringContext",
+            1,
+            context::prim_unsafe_discard_string_context,
+        );
+        self.add_primop("__hasContext", 1, context::prim_has_context);
+        self.add_primop(
+            "__unsafeDiscardOutputDependency",
+            1,
+            context::prim_unsafe_discard_output_dependency,
+        );
+        self.add_primop("__getContext", 1, context::prim_get_context);
+
+        self.add_primop("__isFunction", 1, prim_is_function);
+        self.add_primop("__functionArgs", 1, prim_function_args);
+    }
+}
+
+/// Apply an arithmetic operator: in floating point if either argument is a
+/// float, and in checked integer arithmetic otherwise.
+fn arith<'arena>(
+    state: &EvalState<'arena>,
+    pos: &Pos,
+    args: &[Value<'arena>],
+    (verb, op): (&str, &str),
+    int: fn(NixInt, NixInt) -> Option<NixInt>,
+    float: fn(NixFloat, NixFloat) -> NixFloat,
+) -> NixResult<Value<'arena>> {
+    let a = state.force_value(args[0].clone(), pos)?;
+    let b = state.force_value(args[1].clone(), pos)?;
+    if matches!(a, Value::Float(_)) || matches!(b, Value::Float(_)) {
+        let a = state.force_float(a, pos)?;
+        let b = state.force_float(b, pos)?;
+        Ok(Value::Float(float(a, b)))
+    } else {
+        let a = state.force_int(a, pos)?;
+        let b = state.force_int(b, pos)?;
+        int(a, b).map(Value::Int).ok_or_else(|| {
+            NixError::Eval(format!(
+                "integer overflow in {} {} {} {}, at {}",
+                verb, a, op, b, pos
+            ))
+        })
+    }
+}
+
+fn prim_sub<'arena>(
+    state: &EvalState<'arena>,
+    pos: &Pos,
+    args: &[Value<'arena>],
+) -> NixResult<Value<'arena>> {
+    arith(
+        state,
+        pos,
+        args,
+        ("subtracting", "-"),
+        NixInt::checked_sub,
+        |a, b| a - b,
+    )
+}
+
+fn prim_mul<'arena>(
+    state: &EvalState<'arena>,
+    pos: &Pos,
+    args: &[Value<'arena>],
+) -> NixResult<Value<'arena>> {
+    arith(
+        state,
+        pos,
+        args,
+        ("multiplying", "*"),
+        NixInt::checked_mul,
+        |a, b| a * b,
+    )
+}
+
+fn prim_div<'arena>(
+    state: &EvalState<'arena>,
+    pos: &Pos,
+    args: &[Value<'arena>],
+) -> NixResult<Value<'arena>> {
+    if state.force_float(args[1].clone(), pos)?.into_inner() == 0.0 {
+        return Err(NixError::Eval(format!("division by zero, at {}", pos)));
+    }
+    arith(
+        state,
+        pos,
+        args,
+        ("dividing", "/"),
+        NixInt::checked_div,
+        |a, b| a / b,
+    )
+}
+
+/// Compare two values for `<`: numbers numerically, strings and paths
+/// bytewise, and lists lexicographically.
+fn less_than<'arena>(
+    state: &EvalState<'arena>,
+    a: Value<'arena>,
+    b: Value<'arena>,
+    pos: &Pos,
+) -> NixResult<bool> {
+    let a = state.force_value(a, pos)?;
+    let b = state.force_value(b, pos)?;
+    Ok(match (&a, &b) {
+        (Value::Int(a), Value::Int(b)) => a < b,
+        (Value::Int(a), Value::Float(b)) => NixFloat::from(*a as f64) < *b,
+        (Value::Float(a), Value::Int(b)) => *a < NixFloat::from(*b as f64),
+        (Value::Float(a), Value::Float(b)) => a < b,
+        (Value::String(a), Value::String(b)) => a.s < b.s,
+        (Value::Path(a), Value::Path(b)) => a.as_os_str() < b.as_os_str(),
+        (Value::List(a), Value::List(b)) => {
+            for (x, y) in a.iter().zip(b.iter()) {
+                if !state.eq_values(x.clone(), y.clone())? {
+                    return less_than(state, x.clone(), y.clone(), pos);
+                }
+            }
+            a.len() < b.len()
+        }
+        _ => {
+            return Err(NixError::Eval(format!(
+                "cannot compare {} with {}, at {}",
+                a.show_type(),
+                b.show_type(),
+                pos
+            )))
+        }
+    })
+}
+
+fn prim_less_than<'arena>(
+    state: &EvalState<'arena>,
+    pos: &Pos,
+    args: &[Value<'arena>],
+) -> NixResult<Value<'arena>> {
+    let result = less_than(state, args[0].clone(), args[1].clone(), pos)?;
+    Ok(Value::Bool(result))
+}
+
+/// Convert a value to a string, without copying paths to the store.
+fn prim_to_string<'arena>(
+    state: &EvalState<'arena>,
+    pos: &Pos,
+    args: &[Value<'arena>],
+) -> NixResult<Value<'arena>> {
+    let mut context = NixContext::new();
+    let s = state.coerce_to_string(args[0].clone(), &mut context, true, false, pos)?;
+    Ok(Value::String(NixString { s, context }))
+}
+
+/// `substring start len s`: at most `len` bytes of `s` from `start`, or the
+/// rest of `s` if `len` is negative. The context of `s` is kept.
+///
+/// Strings are UTF-8 here rather than arbitrary bytes like upstream, so
+/// they can't be cut inside a character. Both ends are moved back to the
+/// start of the character they fall in, which keeps
+/// `substring 0 n s + substring n (-1) s == s`.
+fn prim_substring<'arena>(
+    state: &EvalState<'arena>,
+    pos: &Pos,
+    args: &[Value<'arena>],
+) -> NixResult<Value<'arena>> {
+    let start = state.force_int(args[0].clone(), pos)?;
+    let len = state.force_int(args[1].clone(), pos)?;
+    let mut context = NixContext::new();
+    let s = state.coerce_to_string(args[2].clone(), &mut context, false, true, pos)?;
+    if start < 0 {
+        return Err(NixError::Eval(format!(
+            "negative start position in 'substring', at {}",
+            pos
+        )));
+    }
+    let start = (start as usize).min(s.len());
+    let end = if len < 0 {
+        s.len()
+    } else {
+        start.saturating_add(len as usize).min(s.len())
+    };
+    let s = s[floor_char_boundary(&s, start)..floor_char_boundary(&s, end)].to_owned();
+    Ok(Value::String(NixString { s, context }))
+}
+
+/// The start of the character byte `i` of `s` falls in.
+fn floor_char_boundary(s: &str, mut i: usize) -> usize {
+    while !s.is_char_boundary(i) {
+        i -= 1;
+    }
+    i
+}
+
+/// `replaceStrings from to s`: replace each occurrence of a string in
+/// `from` with the corresponding one in `to`, scanning `s` from left to
+/// right. The result has the context of `s`, and of each string in `to`
+/// that was actually used.
+///
+/// `s` is scanned a character at a time rather than a byte at a time like
+/// upstream, so an empty string in `from` matches between characters, and
+/// never inside one.
+fn prim_replace_strings<'arena>(
+    state: &EvalState<'arena>,
+    pos: &Pos,
+    args: &[Value<'arena>],
+) -> NixResult<Value<'arena>> {
+    let from = state.force_list(args[0].clone(), pos)?;
+    let to = state.force_list(args[1].clone(), pos)?;
+    if from.len() != to.len() {
+        return Err(NixError::Eval(format!(
+            "'from' and 'to' arguments to 'replaceStrings' have different lengths, at {}",
+            pos
+        )));
+    }
+    let from = from
+        .iter()
+        .map(|elem| Ok(state.force_string(elem.clone(), pos)?.s))
+        .collect::<NixResult<Vec<_>>>()?;
+    let mut to = to
+        .iter()
+        .map(|elem| state.force_string(elem.clone(), pos))
+        .collect::<NixResult<Vec<_>>>()?;
+    let NixString { s, mut context } = state.force_string(args[2].clone(), pos)?;
+
+    let mut res = String::with_capacity(s.len());
+    // This goes one past the end, so that an empty `from` string matches
+    // there too.
+    let mut p = 0;
+    while p <= s.len() {
+        let rest = &s[p..];
+        let c = rest.chars().next();
+        match from.iter().position(|f| rest.starts_with(f.as_str())) {
+            Some(i) => {
+                res.push_str(&to[i].s);
+                context.append(&mut to[i].context);
+                if from[i].is_empty() {
+                    res.extend(c);
+                    p += c.map_or(1, char::len_utf8);
+                } else {
+                    p += from[i].len();
+                }
+            }
+            None => {
+                res.extend(c);
+                p += c.map_or(1, char::len_utf8);
+            }
+        }
+    }
+    Ok(Value::String(NixString { s: res, context }))
+}
+
+/// Functors aren't functions here, like upstream.
+fn prim_is_function<'arena>(
+    state: &EvalState<'arena>,
+    pos: &Pos,
+    args: &[Value<'arena>],
+) -> NixResult<Value<'arena>> {
+    let value = state.force_value(args[0].clone(), pos)?;
+    Ok(Value::Bool(value.is_function()))
+}
+
+/// The formal arguments of a function, mapped to whether they have a
+/// default.
+fn prim_function_args<'arena>(
+    state: &EvalState<'arena>,
+    pos: &Pos,
+    args: &[Value<'arena>],
+) -> NixResult<Value<'arena>> {
+    let mut attrs = Bindings::new();
+    match state.force_value(args[0].clone(), pos)? {
+        Value::PrimOp(_) | Value::PrimOpApp(_) => {}
+        Value::Lambda(lambda) => {
+            if let Some(formals) = &lambda.fun.formals {
+                for formal in &formals.formals {
+                    attrs.insert(
+                        formal.name,
+                        Value::Bool(formal.def.is_some()),
+                        lambda.fun.pos.clone(),
+                    );
+                }
+            }
+        }
+        _ => {
+            return Err(NixError::Type(format!(
+                "'functionArgs' requires a function, at {}",
+                pos
+            )))
+        }
+    }
+    Ok(Value::Attrs(attrs))
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use crate::eval::tests::eval;
+
+    #[test]
+    fn function_builtins() {
+        assert_eq!(eval("builtins.isFunction (x: x)"), "true");
+        assert_eq!(eval("builtins.isFunction builtins.sub"), "true");
+        assert_eq!(eval("builtins.isFunction (builtins.sub 1)"), "true");
+        assert_eq!(
+            eval("builtins.isFunction { __functor = self: x: x; }"),
+            "false"
+        );
+        assert_eq!(
+            eval("builtins.functionArgs ({ a, b ? 1 }: a)"),
+            "{ a = false; b = true; }"
+        );
+        assert_eq!(eval("builtins.functionArgs (x: x)"), "{ }");
+        assert_eq!(
+            eval("builtins.functionArgs { __functor = self: { a }: a; }"),
+            "error: type error: 'functionArgs' requires a function, at (string):1:1"
+        );
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(eval("2 + 3"), "5");
+        assert_eq!(eval("2 - 3.5"), "-1.5");
+        assert_eq!(eval("2 * 3"), "6");
+        assert_eq!(eval("1.5 * 2"), "3");
+        assert_eq!(eval("7 / 2"), "3");
+        assert_eq!(eval("7 / 2.0"), "3.5");
+        assert_eq!(eval("-(1)"), "-1");
+        assert_eq!(eval("-(2.5)"), "-2.5");
+        assert_eq!(
+            eval(r#""a" - 1"#),
+            "error: type error: value is a string while an integer was expected, at (string):1:1"
+        );
+        assert_eq!(
+            eval(r#"1 + "a""#),
+            "error: cannot add a string to an integer, at (string):1:1"
+        );
+    }
+
+    #[test]
+    fn arithmetic_errors() {
+        assert_eq!(eval("1 / 0"), "error: division by zero, at (string):1:1");
+        assert_eq!(eval("1.0 / 0"), "error: division by zero, at (string):1:1");
+        assert_eq!(
+            eval("9223372036854775807 + 1"),
+            "error: integer overflow in adding 9223372036854775807 + 1, at (string):1:1"
+        );
+        assert_eq!(
+            eval("-9223372036854775807 - 2"),
+            "error: integer overflow in subtracting -9223372036854775807 - 2, at (string):1:1"
+        );
+        assert_eq!(
+            eval("9223372036854775807 * 2"),
+            "error: integer overflow in multiplying 9223372036854775807 * 2, at (string):1:1"
+        );
+        assert_eq!(
+            eval("(-9223372036854775807 - 1) / -1"),
+            "error: integer overflow in dividing -9223372036854775808 / -1, at (string):1:1"
+        );
+    }
+
+    #[test]
+    fn comparison() {
+        assert_eq!(eval("1 < 2"), "true");
+        assert_eq!(eval("1 < 1.5"), "true");
+        assert_eq!(eval(r#""a" < "b""#), "true");
+        assert_eq!(eval("./a < ./b"), "true");
+        assert_eq!(eval("[ 1 2 ] < [ 1 3 ]"), "true");
+        assert_eq!(eval("[ 1 ] < [ 1 2 ]"), "true");
+        assert_eq!(eval("1 <= 1"), "true");
+        assert_eq!(eval("2 > 1"), "true");
+        assert_eq!(eval("1 >= 2"), "false");
+        assert_eq!(
+            eval(r#"1 < "a""#),
+            "error: cannot compare an integer with a string, at (string):1:1"
+        );
+        assert_eq!(
+            eval("{ } < { }"),
+            "error: cannot compare a set with a set, at (string):1:1"
+        );
+    }
+
+    #[test]
+    fn substring() {
+        assert_eq!(eval(r#"builtins.substring 1 2 "hello""#), r#""el""#);
+        assert_eq!(eval(r#"builtins.substring 1 (-1) "hello""#), r#""ello""#);
+        assert_eq!(eval(r#"builtins.substring 3 10 "hello""#), r#""lo""#);
+        assert_eq!(eval(r#"builtins.substring 10 1 "hello""#), r#""""#);
+        assert_eq!(eval(r#"builtins.substring 1 1 "héllo""#), r#""""#);
+        assert_eq!(eval(r#"builtins.substring 2 1 "héllo""#), r#""é""#);
+        assert_eq!(
+            eval(r#"builtins.substring 0 2 "héllo" + builtins.substring 2 (-1) "héllo""#),
+            r#""héllo""#
+        );
+        assert_eq!(
+            eval(r#"builtins.substring (-1) 1 "hello""#),
+            "error: negative start position in 'substring', at (string):1:1"
+        );
+    }
+
+    #[test]
+    fn replace_strings() {
+        assert_eq!(
+            eval(r#"builtins.replaceStrings [ "a" "b" ] [ "b" "a" ] "abab""#),
+            r#""baba""#
+        );
+        assert_eq!(
+            eval(r#"builtins.replaceStrings [ "aa" "a" ] [ "x" "y" ] "aaa""#),
+            r#""xy""#
+        );
+        assert_eq!(
+            eval(r#"builtins.replaceStrings [ "" ] [ "-" ] "ab""#),
+            r#""-a-b-""#
+        );
+        assert_eq!(
+            eval(r#"builtins.replaceStrings [ "" ] [ "-" ] "é""#),
+            r#""-é-""#
+        );
+        assert_eq!(
+            eval(r#"builtins.replaceStrings [ "a" ] [ ] "a""#),
+            "error: 'from' and 'to' arguments to 'replaceStrings' have different lengths, at (string):1:1"
+        );
+    }
+}
@@ -0,0 +1,106 @@
+//! Just enough of the Nix store to work out where paths would be added to
+//! it. Nothing is actually copied: like upstream in read-only mode, we only
+//! compute the store path from the path's contents.
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+pub const STORE_DIR: &str = "/nix/store";
+
+pub const DRV_EXTENSION: &str = ".drv";
+
+const BASE32_CHARS: &[u8; 32] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+/// Nix's own base-32 encoding, as used in store paths.
+pub fn base32(hash: &[u8]) -> String {
+    let len = (hash.len() * 8 - 1) / 5 + 1;
+    (0..len)
+        .rev()
+        .map(|n| {
+            let b = n * 5;
+            let (i, j) = (b / 8, b % 8);
+            let hi = hash.get(i + 1).map_or(0, |&c| (c as u16) << (8 - j));
+            let c = ((hash[i] as u16 >> j) | hi) & 0x1f;
+            BASE32_CHARS[c as usize] as char
+        })
+        .collect()
+}
+
+/// Fold a hash down to `size` bytes by xoring.
+fn compress_hash(hash: &[u8], size: usize) -> Vec<u8> {
+    let mut ret = vec![0; size];
+    for (i, b) in hash.iter().enumerate() {
+        ret[i % size] ^= b;
+    }
+    ret
+}
+
+fn hex(hash: &[u8]) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The store path for an object of the given type and SHA-256 hash, like
+/// upstream's `Store::makeStorePath`.
+pub fn make_store_path(type_: &str, hash: &[u8], name: &str) -> PathBuf {
+    let fingerprint = format!("{}:sha256:{}:{}:{}", type_, hex(hash), STORE_DIR, name);
+    let hash = compress_hash(&Sha256::digest(fingerprint.as_bytes()), 20);
+    Path::new(STORE_DIR).join(format!("{}-{}", base32(&hash), name))
+}
+
+/// The path `path` would be added to the store at, by `nix-store --add` or
+/// by coercing a path value to a string.
+pub fn compute_store_path_for_path(name: &str, path: &Path) -> io::Result<PathBuf> {
+    let mut hasher = Sha256::new();
+    write_str(&mut hasher, b"nix-archive-1");
+    dump_path(&mut hasher, path)?;
+    Ok(make_store_path("source", &hasher.finalize(), name))
+}
+
+/// Write a string in the NAR format: its length, its contents, and padding
+/// to a multiple of 8 bytes.
+fn write_str(hasher: &mut Sha256, s: &[u8]) {
+    hasher.update((s.len() as u64).to_le_bytes());
+    hasher.update(s);
+    hasher.update(&[0; 8][..(8 - s.len() % 8) % 8]);
+}
+
+/// Serialise a file, directory or symlink as a NAR.
+fn dump_path(hasher: &mut Sha256, path: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    write_str(hasher, b"(");
+    write_str(hasher, b"type");
+    if metadata.is_dir() {
+        write_str(hasher, b"directory");
+        let mut entries = fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort();
+        for name in entries {
+            write_str(hasher, b"entry");
+            write_str(hasher, b"(");
+            write_str(hasher, b"name");
+            write_str(hasher, name.to_string_lossy().as_bytes());
+            write_str(hasher, b"node");
+            dump_path(hasher, &path.join(&name))?;
+            write_str(hasher, b")");
+        }
+    } else if metadata.file_type().is_symlink() {
+        write_str(hasher, b"symlink");
+        write_str(hasher, b"target");
+        write_str(hasher, fs::read_link(path)?.to_string_lossy().as_bytes());
+    } else {
+        write_str(hasher, b"regular");
+        if metadata.permissions().mode() & 0o100 != 0 {
+            write_str(hasher, b"executable");
+            write_str(hasher, b"");
+        }
+        write_str(hasher, b"contents");
+        write_str(hasher, &fs::read(path)?);
+    }
+    write_str(hasher, b")");
+    Ok(())
+}
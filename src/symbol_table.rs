@@ -1,12 +1,220 @@
-use std::collections::HashSet;
+use std::cmp::Ordering;
+use std::fmt;
+use std::sync::{Mutex, OnceLock};
 
-pub type Symbol<'arena> = &'arena str;
+use shawshank::ArenaSet;
 
-pub struct SymbolTable(HashSet<String>);
+/// An interned string, like an identifier or attribute name.
+///
+/// Symbols are compared and hashed by their ID, in O(1), and resolve back to
+/// their string with `as_str`. There's a single table for the whole program,
+/// so a symbol can be shown without knowing where it came from, like
+/// upstream's symbols, which point at their string.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Symbol(u32);
+
+macro_rules! well_known_symbols {
+    ($($name:ident = $id:literal => $s:literal,)*) => {
+        impl Symbol {
+            $(pub const $name: Symbol = Symbol($id);)*
+        }
+
+        const WELL_KNOWN: &[(Symbol, &str)] = &[$((Symbol::$name, $s)),*];
+    };
+}
+
+// These are interned when the table is created, in this order, so they can
+// be used as constants.
+well_known_symbols! {
+    WITH = 0 => "<with>",
+    OUT_PATH = 1 => "outPath",
+    DRV_PATH = 2 => "drvPath",
+    TYPE = 3 => "type",
+    META = 4 => "meta",
+    NAME = 5 => "name",
+    VALUE = 6 => "value",
+    SYSTEM = 7 => "system",
+    OVERRIDES = 8 => "__overrides",
+    OUTPUTS = 9 => "outputs",
+    OUTPUT_NAME = 10 => "outputName",
+    IGNORE_NULLS = 11 => "__ignoreNulls",
+    FILE = 12 => "file",
+    LINE = 13 => "line",
+    COLUMN = 14 => "column",
+    FUNCTOR = 15 => "__functor",
+    TO_STRING = 16 => "__toString",
+    RIGHT = 17 => "right",
+    WRONG = 18 => "wrong",
+    STRUCTURED_ATTRS = 19 => "__structuredAttrs",
+    BUILDER = 20 => "builder",
+    ARGS = 21 => "args",
+    OUTPUT_HASH = 22 => "outputHash",
+    OUTPUT_HASH_ALGO = 23 => "outputHashAlgo",
+    OUTPUT_HASH_MODE = 24 => "outputHashMode",
+    DERIVATION_NIX = 25 => "//builtin/derivation.nix",
+}
+
+impl Symbol {
+    /// Intern `s`.
+    pub fn new(s: &str) -> Self {
+        SymbolTable::global().create(s)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        SymbolTable::global().resolve(self)
+    }
+
+    /// The symbol's ID, which orders symbols by when they were interned.
+    /// Sorting by this is cheaper than by name, where the order doesn't
+    /// matter as long as it's deterministic.
+    pub fn id(self) -> u32 {
+        self.0
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl From<Symbol> for String {
+    fn from(sym: Symbol) -> Self {
+        sym.as_str().to_owned()
+    }
+}
+
+/// Symbols are ordered by their strings, so sorting attributes by name
+/// works like upstream. This locks the table, so prefer sorting by `id`, or
+/// with a cached key, where possible.
+impl PartialOrd for Symbol {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Symbol {
+    fn cmp(&self, other: &Self) -> Ordering {
+        if self == other {
+            Ordering::Equal
+        } else {
+            SymbolTable::global().compare(*self, *other)
+        }
+    }
+}
+
+/// Interned strings, which are only ever added.
+///
+/// Each string is in its own `Box`, which isn't freed until the table is,
+/// since strings are never removed: the set is private and nothing here
+/// calls `ArenaSet::disintern`. Growing the set only moves the boxes, not
+/// the strings they point to. So a string can be borrowed for as long as
+/// the table, even after the lock is released, which is what `resolve`
+/// does; for the global table, which is never dropped, that's `'static`.
+///
+/// That also means nothing interned is ever freed. Identifiers in the
+/// source are bounded by its size, but names computed while evaluating,
+/// from dynamic attributes or `listToAttrs`, stay for the rest of the
+/// process, so don't intern strings which aren't names.
+pub struct SymbolTable(Mutex<ArenaSet<Box<str>, u32>>);
 
 impl SymbolTable {
-    pub fn create(&mut self, s: String) -> Symbol<'_> {
-        self.0.insert(s);
-        self.0.get(&s).unwrap()
+    /// The table every symbol is interned in.
+    pub fn global() -> &'static SymbolTable {
+        static TABLE: OnceLock<SymbolTable> = OnceLock::new();
+        TABLE.get_or_init(|| {
+            let table = SymbolTable(Mutex::new(ArenaSet::new().unwrap()));
+            for &(sym, s) in WELL_KNOWN {
+                assert_eq!(table.create(s), sym);
+            }
+            table
+        })
+    }
+
+    /// Intern `s`, returning the symbol shared with every other copy of it.
+    pub fn create(&self, s: &str) -> Symbol {
+        let mut set = self.0.lock().unwrap();
+        Symbol(set.intern(s).expect("too many symbols"))
+    }
+
+    pub fn resolve(&self, sym: Symbol) -> &str {
+        let set = self.0.lock().unwrap();
+        let s: *const str = set.resolve::<_, str>(sym.0).unwrap();
+        // SAFETY: The string is in a box which lives as long as the table
+        // and is never mutated, as explained above.
+        unsafe { &*s }
+    }
+
+    /// Sort `items` by the strings of their symbols, locking the table once
+    /// for the whole sort rather than for each comparison.
+    pub fn sort_by_name<T>(&self, items: &mut [T], key: impl Fn(&T) -> Symbol) {
+        let set = self.0.lock().unwrap();
+        let name = |item: &T| set.resolve::<_, str>(key(item).0).unwrap();
+        items.sort_by(|a, b| name(a).cmp(name(b)));
+    }
+
+    /// Compare the strings of two symbols, locking the table once.
+    fn compare(&self, a: Symbol, b: Symbol) -> Ordering {
+        let set = self.0.lock().unwrap();
+        let a = set.resolve::<_, str>(a.0).unwrap();
+        let b = set.resolve::<_, str>(b.0).unwrap();
+        a.cmp(b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interning() {
+        let a = Symbol::new("symbol_table_test_a");
+        assert_eq!(Symbol::new("symbol_table_test_a"), a);
+        assert_ne!(Symbol::new("symbol_table_test_b"), a);
+        assert_eq!(a.as_str(), "symbol_table_test_a");
+        assert_eq!(String::from(a), "symbol_table_test_a");
+    }
+
+    #[test]
+    fn well_known_symbols() {
+        for &(sym, s) in WELL_KNOWN {
+            assert_eq!(Symbol::new(s), sym);
+            assert_eq!(sym.as_str(), s);
+        }
+    }
+
+    #[test]
+    fn ordering() {
+        let mut syms = [Symbol::new("b"), Symbol::OUT_PATH, Symbol::new("a")];
+        syms.sort();
+        let names = syms.iter().map(|sym| sym.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["a", "b", "outPath"]);
+
+        let mut items = [
+            (Symbol::new("b"), 1),
+            (Symbol::OUT_PATH, 2),
+            (Symbol::new("a"), 3),
+        ];
+        SymbolTable::global().sort_by_name(&mut items, |&(sym, _)| sym);
+        assert_eq!(items.iter().map(|&(_, n)| n).collect::<Vec<_>>(), [3, 1, 2]);
+    }
+
+    #[test]
+    fn resolved_strings_outlive_growth() {
+        let s = Symbol::new("symbol_table_test_stable").as_str();
+        for i in 0..10_000 {
+            Symbol::new(&format!("symbol_table_test_{}", i));
+        }
+        assert_eq!(s, "symbol_table_test_stable");
+        assert!(std::ptr::eq(
+            s,
+            Symbol::new("symbol_table_test_stable").as_str()
+        ));
     }
 }
@@ -1,51 +1,164 @@
-use std::collections::HashSet;
-use std::hash::{Hash, Hasher};
+use std::cell::{Ref, RefCell};
+use std::collections::{BTreeSet, HashSet};
+use std::fmt;
+use std::fmt::{Display, Formatter, Write};
 use std::path::PathBuf;
+use std::rc::Rc;
 
 use ordered_float::OrderedFloat;
 
+use crate::arena::Arena;
 pub use crate::attr_set::Bindings;
 pub use crate::env::Env;
+use crate::nix_expr::show_float;
 pub use crate::nix_expr::{Expr, ExprExt, ExprLambda};
 pub use crate::primops::PrimOp;
 
 pub type NixInt = i64;
 pub type NixFloat = OrderedFloat<f64>;
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Thunk<'arena> {
-    pub env: Env<'arena>,
-    pub expr: Expr<'arena>,
+    pub env: &'arena Env<'arena>,
+    pub expr: &'arena Expr,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct App<'arena> {
     pub left: Value<'arena>,
     pub right: Value<'arena>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum ThunkState<'arena> {
+    /// An expression which hasn't been evaluated yet.
+    Thunk(Thunk<'arena>),
+    /// The result of forcing the thunk, or `Value::Blackhole` while it's
+    /// being forced.
+    Value(Value<'arena>),
+}
+
+/// A lazily evaluated value, shared by every reference to it. Forcing it
+/// overwrites it with its result, so it's evaluated at most once.
+#[derive(Clone, Copy, Debug)]
+pub struct SharedThunk<'arena>(&'arena RefCell<ThunkState<'arena>>);
+
+impl<'arena> SharedThunk<'arena> {
+    pub fn new(arena: &'arena Arena<'arena>, env: &'arena Env<'arena>, expr: &'arena Expr) -> Self {
+        Self::from_state(arena, ThunkState::Thunk(Thunk { env, expr }))
+    }
+
+    /// A thunk which is filled in later with `replace_state`; forcing it
+    /// before then is an infinite recursion.
+    pub fn blackhole(arena: &'arena Arena<'arena>) -> Self {
+        Self::from_state(arena, ThunkState::Value(Value::Blackhole))
+    }
+
+    fn from_state(arena: &'arena Arena<'arena>, state: ThunkState<'arena>) -> Self {
+        SharedThunk(arena.alloc_thunk(state))
+    }
+
+    /// The address of the thunk, which identifies it.
+    pub fn as_ptr(&self) -> *const () {
+        self.0 as *const RefCell<_> as *const ()
+    }
+
+    pub fn state(&self) -> Ref<'_, ThunkState<'arena>> {
+        self.0.borrow()
+    }
+
+    /// Overwrite the thunk, e.g. with its value once it has been forced,
+    /// returning what it was before.
+    pub fn replace_state(&self, state: ThunkState<'arena>) -> ThunkState<'arena> {
+        self.0.replace(state)
+    }
+}
+
+/// Thunks are equal if they are the same thunk.
+impl PartialEq for SharedThunk<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self.0, other.0)
+    }
+}
+
+/// A function: its expression and the environment it closes over. Both are
+/// in the arena, so this is just two references.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Lambda<'arena> {
-    pub env: Env<'arena>,
-    pub fun: ExprLambda<'arena>,
+    pub env: &'arena Env<'arena>,
+    pub fun: &'arena ExprLambda,
+}
+
+/// Something in the store which a string refers to, such as the output of
+/// a derivation interpolated into it.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ContextElem {
+    /// A plain store path, like a source copied to the store.
+    Opaque(String),
+    /// A derivation and, recursively, everything it depends on, as
+    /// referred to by its `drvPath`. Serialised as `=/nix/store/...drv`.
+    AllOutputs(String),
+    /// One output of a derivation, as referred to by its `outPath`.
+    /// Serialised as `!out!/nix/store/...drv`.
+    Output { drv: String, output: String },
 }
 
-#[derive(Debug, Hash, PartialEq)]
+impl ContextElem {
+    /// Parse the serialised form of a context element, like upstream's
+    /// `decodeContext`.
+    pub fn decode(s: &str) -> Self {
+        if let Some(rest) = s.strip_prefix('!') {
+            if let Some(index) = rest.find('!') {
+                return ContextElem::Output {
+                    drv: rest[index + 1..].to_owned(),
+                    output: rest[..index].to_owned(),
+                };
+            }
+        }
+        match s.strip_prefix('=') {
+            Some(drv) => ContextElem::AllOutputs(drv.to_owned()),
+            None => ContextElem::Opaque(s.to_owned()),
+        }
+    }
+
+    /// The store path referred to; for outputs, this is the derivation.
+    pub fn path(&self) -> &str {
+        match self {
+            ContextElem::Opaque(path) | ContextElem::AllOutputs(path) => path,
+            ContextElem::Output { drv, .. } => drv,
+        }
+    }
+}
+
+/// Serialises the element like upstream, e.g. for `derivationStrict`.
+impl Display for ContextElem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ContextElem::Opaque(path) => f.write_str(path),
+            ContextElem::AllOutputs(drv) => write!(f, "={}", drv),
+            ContextElem::Output { drv, output } => write!(f, "!{}!{}", output, drv),
+        }
+    }
+}
+
+pub type NixContext = BTreeSet<ContextElem>;
+
+#[derive(Clone, Debug, Hash, PartialEq)]
 pub struct NixString {
     pub s: String,
-    pub context: Vec<String>,
+    pub context: NixContext,
 }
 
 impl NixString {
     pub fn new(s: String) -> Self {
         Self {
             s,
-            context: Vec::new(),
+            context: NixContext::new(),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Value<'arena> {
     Int(NixInt),
     Bool(bool),
@@ -53,10 +166,10 @@ pub enum Value<'arena> {
     Path(PathBuf),
     Null,
     Attrs(Bindings<'arena>),
-    /// `List` represents `tList1`, `tList2` and `tListN`.
-    List(Vec<Value<'arena>>),
-    Thunk(Thunk<'arena>),
-    App(Box<App<'arena>>),
+    /// `List` represents `tList1`, `tList2` and `tListN`. The elements are
+    /// shared between copies of the list, since lists are immutable.
+    List(Rc<[Value<'arena>]>),
+    Thunk(SharedThunk<'arena>),
     Lambda(Lambda<'arena>),
     Blackhole,
     PrimOp(PrimOp),
@@ -65,7 +178,144 @@ pub enum Value<'arena> {
     Float(NixFloat),
 }
 
-struct ValueDisplay<'arena> {
-    value: Value<'arena>,
-    active: HashSet<Value<'arena>>,
+impl<'arena> Value<'arena> {
+    pub fn is_function(&self) -> bool {
+        matches!(
+            self,
+            Value::Lambda(_) | Value::PrimOp(_) | Value::PrimOpApp(_)
+        )
+    }
+
+    /// Describe the type of this value for error messages, like "a set".
+    pub fn show_type(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "an integer",
+            Value::Bool(_) => "a Boolean",
+            Value::String(s) if !s.context.is_empty() => "a string with context",
+            Value::String(_) => "a string",
+            Value::Path(_) => "a path",
+            Value::Null => "null",
+            Value::Attrs(_) => "a set",
+            Value::List(_) => "a list",
+            Value::Thunk(_) => "a thunk",
+            Value::Lambda(_) => "a function",
+            Value::Blackhole => "a black hole",
+            Value::PrimOp(_) => "a built-in function",
+            Value::PrimOpApp(_) => "a partially applied built-in function",
+            Value::External => "an external value",
+            Value::Float(_) => "a float",
+        }
+    }
+}
+
+/// Prints the value like upstream's `printValue`, as used by `nix-instantiate
+/// --eval`. Unevaluated parts are printed as `<CODE>`, and sets and lists
+/// which were already printed as `«repeated»`, so cyclic values like
+/// `let x = { inherit x; }; in x` can be printed.
+impl Display for Value<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.fmt_seen(f, &mut HashSet::new())
+    }
+}
+
+impl Value<'_> {
+    /// `seen` has the sets and lists printed so far, by the address of
+    /// their contents.
+    fn fmt_seen(&self, f: &mut Formatter<'_>, seen: &mut HashSet<*const ()>) -> fmt::Result {
+        match self {
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::String(s) => {
+                f.write_char('"')?;
+                for c in s.s.chars() {
+                    match c {
+                        '"' | '\\' => write!(f, "\\{}", c)?,
+                        '\n' => f.write_str("\\n")?,
+                        '\r' => f.write_str("\\r")?,
+                        '\t' => f.write_str("\\t")?,
+                        c => f.write_char(c)?,
+                    }
+                }
+                f.write_char('"')
+            }
+            Value::Path(path) => write!(f, "{}", path.display()),
+            Value::Null => f.write_str("null"),
+            Value::Attrs(attrs) => {
+                if !attrs.is_empty() && !seen.insert(Rc::as_ptr(&attrs.0) as *const ()) {
+                    return f.write_str("«repeated»");
+                }
+                f.write_str("{ ")?;
+                for attr in attrs.sorted() {
+                    write!(f, "{} = ", attr.name)?;
+                    attr.value.value.fmt_seen(f, seen)?;
+                    f.write_str("; ")?;
+                }
+                f.write_str("}")
+            }
+            Value::List(elems) => {
+                if !elems.is_empty() && !seen.insert(Rc::as_ptr(elems) as *const ()) {
+                    return f.write_str("«repeated»");
+                }
+                f.write_str("[ ")?;
+                for elem in elems.iter() {
+                    elem.fmt_seen(f, seen)?;
+                    f.write_char(' ')?;
+                }
+                f.write_str("]")
+            }
+            Value::Thunk(thunk) => match &*thunk.state() {
+                ThunkState::Value(value) => value.fmt_seen(f, seen),
+                ThunkState::Thunk(_) => f.write_str("<CODE>"),
+            },
+            Value::Lambda(_) => f.write_str("<LAMBDA>"),
+            Value::Blackhole => f.write_str("«black hole»"),
+            Value::PrimOp(_) => f.write_str("<PRIMOP>"),
+            Value::PrimOpApp(_) => f.write_str("<PRIMOP-APP>"),
+            Value::External => f.write_str("<EXTERNAL>"),
+            Value::Float(n) => f.write_str(&show_float(n.into_inner())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn context_elem_round_trip() {
+        let drv = "/nix/store/aaaa-foo.drv";
+        for (s, elem) in [
+            (
+                "/nix/store/bbbb-src",
+                ContextElem::Opaque("/nix/store/bbbb-src".to_owned()),
+            ),
+            (
+                "=/nix/store/aaaa-foo.drv",
+                ContextElem::AllOutputs(drv.to_owned()),
+            ),
+            (
+                "!out!/nix/store/aaaa-foo.drv",
+                ContextElem::Output {
+                    drv: drv.to_owned(),
+                    output: "out".to_owned(),
+                },
+            ),
+        ] {
+            assert_eq!(ContextElem::decode(s), elem);
+            assert_eq!(elem.to_string(), s);
+        }
+    }
+
+    #[test]
+    fn context_elem_path() {
+        let drv = "/nix/store/aaaa-foo.drv";
+        assert_eq!(ContextElem::decode("=/nix/store/aaaa-foo.drv").path(), drv);
+        assert_eq!(
+            ContextElem::decode("!dev!/nix/store/aaaa-foo.drv").path(),
+            drv
+        );
+        assert_eq!(ContextElem::decode("!broken").path(), "!broken");
+    }
 }